@@ -0,0 +1,527 @@
+use crate::keyexpr::Definitions;
+use crate::mapping::*;
+use crate::remapper::{is_modifier_code, ComputeObserver};
+use crate::rules::{Keys, RuleSet};
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    /// One problem per line
+    Text,
+    /// A JSON array of problems
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Serialize)]
+struct Problem {
+    severity: Severity,
+    /// A stable identifier for the class of problem
+    kind: &'static str,
+    /// The file that defines the rule, if the problem is with a rule
+    file: Option<String>,
+    /// The rule within that file, eg: `remap #3`
+    rule: Option<String>,
+    message: String,
+}
+
+impl Problem {
+    fn for_rule(
+        severity: Severity,
+        kind: &'static str,
+        origin: &RuleOrigin,
+        message: String,
+    ) -> Self {
+        Self {
+            severity,
+            kind,
+            file: Some(origin.file.display().to_string()),
+            rule: Some(format!("{} #{}", origin.section, origin.index)),
+            message,
+        }
+    }
+}
+
+fn key_list<'a>(defs: &Definitions, keys: impl IntoIterator<Item = &'a KeyCode>) -> String {
+    let mut names: Vec<String> = keys.into_iter().map(|k| defs.code_name(k)).collect();
+    names.sort();
+    format!("[{}]", names.join(", "))
+}
+
+/// The name of a dual_role input: a single key, or a chord of keys
+fn chord_name(defs: &Definitions, keys: &[KeyCode]) -> String {
+    keys.iter()
+        .map(|k| defs.code_name(k))
+        .collect::<Vec<_>>()
+        .join("+")
+}
+
+/// The ways of holding the keys of `input`, modifiers first: one for
+/// each combination of the keys of its side-agnostic modifiers.  None
+/// if part of it can't be held, such as an axis or a virtual modifier.
+fn held_combinations(
+    input: &HashSet<KeyCodeWrapper>,
+    modifiers: &HashSet<KeyCode>,
+) -> Option<Vec<Vec<KeyCode>>> {
+    let mut combinations = vec![vec![]];
+    for i in input {
+        let choices = match (i.code, logical_modifier_keys(&i.code)) {
+            (_, Some(sides)) => sides.to_vec(),
+            (EventCode::EV_KEY(_), None) => vec![i.code],
+            _ => return None,
+        };
+        combinations = combinations
+            .into_iter()
+            .flat_map(|held: Vec<KeyCode>| {
+                choices.iter().map(move |choice| {
+                    let mut held = held.clone();
+                    held.push(*choice);
+                    held
+                })
+            })
+            .collect();
+    }
+    for held in &mut combinations {
+        held.sort_by_key(|k| !is_modifier_code(k, modifiers));
+    }
+    Some(combinations)
+}
+
+/// Records the indices of the remap rules that match
+#[derive(Default)]
+struct Matched(Vec<usize>);
+
+impl ComputeObserver for Matched {
+    fn remap_matched(&mut self, idx: usize, _released: Keys) {
+        self.0.push(idx);
+    }
+}
+
+/// The keys that a matching remap rule hides from the rules after it:
+/// its non-modifier inputs and outputs
+fn consumed_keys(
+    input: &HashSet<KeyCodeWrapper>,
+    output: &[KeyCodeWrapper],
+    modifiers: &HashSet<KeyCode>,
+) -> HashSet<KeyCode> {
+    input
+        .iter()
+        .chain(output.iter())
+        .filter(|k| matches!(k.code, EventCode::EV_KEY(_)) && !is_modifier_code(&k.code, modifiers))
+        .map(|k| k.code)
+        .collect()
+}
+
+/// The parts of a remap rule that the checks look at
+struct Remap<'a> {
+    /// The index of the mapping
+    idx: usize,
+    input: &'a HashSet<KeyCodeWrapper>,
+    output: &'a Vec<KeyCodeWrapper>,
+    policy: ModifierPolicy,
+    unless: HashSet<KeyCode>,
+    exact: bool,
+    origin: &'a RuleOrigin,
+}
+
+impl<'a> Remap<'a> {
+    /// Returns true if this rule matches whenever `other` would, as far
+    /// as its `modifiers` policy, `unless` and `exact` are concerned
+    fn less_restricted_than(&self, other: &Remap) -> bool {
+        self.unless.is_subset(&other.unless)
+            && (!self.exact || other.exact)
+            && (self.policy != ModifierPolicy::Exact || other.policy == ModifierPolicy::Exact)
+    }
+}
+
+/// Returns the earlier rule that keeps `remap` from ever firing, by
+/// matching first and consuming part of its input however that input
+/// is held, and whatever else is held along with it
+fn shadowing_rule<'a>(
+    rules: &RuleSet,
+    remaps: &'a [Remap<'a>],
+    remap: &Remap,
+) -> Option<&'a Remap<'a>> {
+    let mut shadow = None;
+    for held in held_combinations(remap.input, &rules.modifiers)? {
+        let mut matched = Matched::default();
+        rules.compute_keys(&held, &mut matched);
+        if matched.0.contains(&remap.idx) {
+            return None;
+        }
+        // Holding more keys could stop a more restricted rule from
+        // matching, and let this one fire
+        let prior = remaps.iter().find(|prior| {
+            prior.idx < remap.idx
+                && matched.0.contains(&prior.idx)
+                && prior.less_restricted_than(remap)
+                && consumed_keys(prior.input, prior.output, &rules.modifiers)
+                    .iter()
+                    .any(|k| held.contains(k))
+        })?;
+        shadow.get_or_insert(prior);
+    }
+    shadow
+}
+
+fn check_mappings(config: &MappingConfig, rule_set: &RuleSet) -> Vec<Problem> {
+    let mut problems = vec![];
+    let rules: Vec<(&Mapping, &RuleOrigin)> =
+        config.mappings.iter().zip(config.origins.iter()).collect();
+
+    let mut dual_inputs: Vec<(&Vec<KeyCode>, &Vec<KeyCode>, &RuleOrigin)> = vec![];
+    for (map, origin) in &rules {
+        if let Mapping::DualRole {
+            input,
+            tap,
+            hold,
+            unless,
+            ..
+        } = map
+        {
+            // A prior rule for some of the same keys, with fewer
+            // `unless` keys, applies first whenever this one would
+            let duplicate = dual_inputs.iter().find(|(prior_input, prior_unless, _)| {
+                prior_input.iter().all(|k| input.contains(k))
+                    && prior_unless.iter().all(|k| unless.contains(k))
+            });
+            if let Some((prior_input, _, prior)) = duplicate {
+                let reason = if prior_input.len() == input.len() {
+                    "has the same input"
+                } else {
+                    "applies first to part of its input"
+                };
+                problems.push(Problem::for_rule(
+                    Severity::Error,
+                    "duplicate-input",
+                    origin,
+                    format!(
+                        "dual_role for {} is never used because {} {}",
+                        chord_name(&config.definitions, input),
+                        prior,
+                        reason
+                    ),
+                ));
+            } else {
+                dual_inputs.push((input, unless, origin));
+            }
+            for code in tap.iter().chain(hold.iter()) {
+                check_output(&mut problems, origin, code);
+            }
+        }
+    }
+
+    let remaps: Vec<Remap> = rules
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, (map, origin))| match map {
+            Mapping::Remap {
+                input,
+                output,
+                modifiers,
+                unless,
+                exact,
+                ..
+            } => Some(Remap {
+                idx,
+                input,
+                output,
+                policy: *modifiers,
+                unless: unless.iter().cloned().collect(),
+                exact: *exact,
+                origin,
+            }),
+            _ => None,
+        })
+        .collect();
+
+    for (idx, remap) in remaps.iter().enumerate() {
+        let Remap { input, origin, .. } = remap;
+        for o in remap.output.iter() {
+            check_output(&mut problems, origin, &o.code);
+        }
+
+        // A rule that is restricted by its modifiers policy, `unless`
+        // or `exact` lets later rules with the same input fire
+        let duplicate = remaps[..idx]
+            .iter()
+            .find(|prior| prior.input == *input && prior.less_restricted_than(remap));
+        if let Some(Remap { origin: prior, .. }) = duplicate {
+            problems.push(Problem::for_rule(
+                Severity::Error,
+                "duplicate-input",
+                origin,
+                format!(
+                    "remap for {} is never used because {} has the same input",
+                    key_list(&config.definitions, input.iter().map(|k| &k.code)),
+                    prior
+                ),
+            ));
+            continue;
+        }
+
+        // DualRole inputs are replaced by their hold keys before any
+        // remap rules are considered, unless they have `unless` keys
+        let duals: Vec<&(&Vec<KeyCode>, &Vec<KeyCode>, &RuleOrigin)> = dual_inputs
+            .iter()
+            .filter(|(chord, unless, _)| {
+                unless.is_empty() && chord.iter().all(|k| input.iter().any(|i| i.code == *k))
+            })
+            .collect();
+        for (chord, _, dual_origin) in &duals {
+            problems.push(Problem::for_rule(
+                Severity::Warning,
+                "dual-role-input",
+                origin,
+                format!(
+                    "remap can never fire because its input {} is the dual_role input of {}",
+                    chord_name(&config.definitions, chord),
+                    dual_origin
+                ),
+            ));
+        }
+
+        if duals.is_empty() {
+            if let Some(Remap { origin: prior, .. }) = shadowing_rule(rule_set, &remaps, remap) {
+                problems.push(Problem::for_rule(
+                    Severity::Warning,
+                    "unreachable",
+                    origin,
+                    format!(
+                        "remap for {} can never fire because {} always matches \
+                         first and consumes part of its input",
+                        key_list(&config.definitions, input.iter().map(|k| &k.code)),
+                        prior
+                    ),
+                ));
+            }
+        }
+
+        for i in input.iter() {
+            if matches!(i.code, EventCode::EV_KEY(_)) || i.scale.abs() <= 1 {
+                continue;
+            }
+            let opposite = KeyCodeWrapper {
+                code: i.code,
+                scale: -i.scale,
+            };
+            let others: HashSet<&KeyCodeWrapper> = input.iter().filter(|k| *k != i).collect();
+            let mirrored = remaps.iter().any(|other| {
+                other.input.contains(&opposite)
+                    && other
+                        .input
+                        .iter()
+                        .filter(|k| **k != opposite)
+                        .collect::<HashSet<_>>()
+                        == others
+            });
+            if !mirrored {
+                problems.push(Problem::for_rule(
+                    Severity::Warning,
+                    "one-direction",
+                    origin,
+                    format!(
+                        "{} has an input scale but only the {} direction is remapped; \
+                         the other direction passes through unscaled",
+                        code_name(&i.code),
+                        if i.scale.is_negative() {
+                            "negative"
+                        } else {
+                            "positive"
+                        }
+                    ),
+                ));
+            }
+        }
+    }
+
+    problems
+}
+
+/// Reports output codes that the output device may not be able to emit
+fn check_output(problems: &mut Vec<Problem>, origin: &RuleOrigin, code: &KeyCode) {
+    match code {
+        EventCode::EV_KEY(evdev_rs::enums::EV_KEY::KEY_RESERVED) => {
+            problems.push(Problem::for_rule(
+                Severity::Error,
+                "unsupported-output",
+                origin,
+                format!("{} cannot be emitted", code_name(code)),
+            ));
+        }
+        EventCode::EV_REL(_) => {}
+        code if is_key_code(code) && as_scan_code(code).is_none() => {}
+        EventCode::EV_ABS(_) => {
+            problems.push(Problem::for_rule(
+                Severity::Warning,
+                "unsupported-output",
+                origin,
+                format!(
+                    "{} can only be emitted if the input device already reports it, \
+                     because evremap cannot enable an axis that it has no range for",
+                    code_name(code)
+                ),
+            ));
+        }
+        _ => {
+            problems.push(Problem::for_rule(
+                Severity::Error,
+                "unsupported-output",
+                origin,
+                format!("{} cannot be enabled on the output device", code_name(code)),
+            ));
+        }
+    }
+}
+
+/// Load the configuration at `path` and report any problems with it.
+/// Returns true if no problems were found.
+pub fn check_config(path: &Path, format: OutputFormat) -> Result<bool> {
+    let checked = MappingConfig::from_file(path).and_then(|config| {
+        let rules = RuleSet::new(config.mappings.clone(), config.modifiers.clone())?;
+        Ok(check_mappings(&config, &rules))
+    });
+    let problems = match checked {
+        Ok(problems) => problems,
+        Err(err) => vec![Problem {
+            severity: Severity::Error,
+            kind: "load",
+            file: Some(path.display().to_string()),
+            rule: None,
+            message: format!("{:#}", err),
+        }],
+    };
+
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&problems)?),
+        OutputFormat::Text => {
+            for problem in &problems {
+                let location = match (&problem.file, &problem.rule) {
+                    (Some(file), Some(rule)) => format!("{}: {}: ", file, rule),
+                    (Some(file), None) => format!("{}: ", file),
+                    _ => String::new(),
+                };
+                let severity = match problem.severity {
+                    Severity::Error => "error",
+                    Severity::Warning => "warning",
+                };
+                println!(
+                    "{}{}[{}]: {}",
+                    location, severity, problem.kind, problem.message
+                );
+            }
+            if problems.is_empty() {
+                println!("{}: no problems found", path.display());
+            }
+        }
+    }
+
+    Ok(problems.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The rules that the check reports as unreachable, eg: `remap #2`
+    fn unreachable(name: &str, toml: &str) -> Vec<String> {
+        let config = MappingConfig::from_toml(name, toml);
+        let rules = RuleSet::new(config.mappings.clone(), config.modifiers.clone()).unwrap();
+        check_mappings(&config, &rules)
+            .into_iter()
+            .filter(|problem| problem.kind == "unreachable")
+            .filter_map(|problem| problem.rule)
+            .collect()
+    }
+
+    #[test]
+    fn side_agnostic_rule_shadowed_by_its_key() {
+        let toml = r#"
+            [[remap]]
+            input = ["KEY_C"]
+            output = ["KEY_X"]
+
+            [[remap]]
+            input = ["CTRL", "KEY_C"]
+            output = ["KEY_Y"]
+        "#;
+        assert_eq!(unreachable("shadowed-ctrl-c", toml), ["remap #2"]);
+    }
+
+    #[test]
+    fn side_agnostic_rule_shadows_either_side() {
+        let toml = r#"
+            [[remap]]
+            input = ["CTRL", "KEY_C"]
+            output = ["KEY_X"]
+
+            [[remap]]
+            input = ["KEY_RIGHTCTRL", "KEY_C"]
+            output = ["KEY_Y"]
+        "#;
+        assert_eq!(unreachable("ctrl-c-shadows", toml), ["remap #2"]);
+    }
+
+    #[test]
+    fn one_side_leaves_the_other_reachable() {
+        let toml = r#"
+            [[remap]]
+            input = ["KEY_LEFTCTRL", "KEY_C"]
+            output = ["KEY_X"]
+
+            [[remap]]
+            input = ["CTRL", "KEY_C"]
+            output = ["KEY_Y"]
+        "#;
+        assert!(unreachable("left-ctrl-c", toml).is_empty());
+    }
+
+    #[test]
+    fn restricted_rule_does_not_shadow() {
+        let toml = r#"
+            [[remap]]
+            input = ["KEY_C"]
+            output = ["KEY_X"]
+            modifiers = "exact"
+
+            [[remap]]
+            input = ["CTRL", "KEY_C"]
+            output = ["KEY_Y"]
+
+            [[remap]]
+            input = ["KEY_V"]
+            output = ["KEY_X"]
+            unless = ["SHIFT"]
+
+            [[remap]]
+            input = ["CTRL", "KEY_V"]
+            output = ["KEY_Y"]
+        "#;
+        assert!(unreachable("restricted", toml).is_empty());
+    }
+
+    #[test]
+    fn masked_rule_shadows() {
+        let toml = r#"
+            [[remap]]
+            input = ["KEY_C"]
+            output = ["KEY_X"]
+            mask = ["SHIFT"]
+
+            [[remap]]
+            input = ["SHIFT", "KEY_C"]
+            output = ["KEY_Y"]
+        "#;
+        assert_eq!(unreachable("masked", toml), ["remap #2"]);
+    }
+}
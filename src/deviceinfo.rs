@@ -1,7 +1,7 @@
 use anyhow::{bail, Context, Result};
 use evdev_rs::{Device, DeviceWrapper};
 use std::cmp::Ordering;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone)]
 pub struct DeviceInfo {
@@ -65,6 +65,32 @@ impl DeviceInfo {
         Ok(devices_with_name.remove(0))
     }
 
+    /// Returns this device followed by any other event nodes that
+    /// share its sysfs parent or USB device.  Many keyboards expose
+    /// their media and power keys via separate "Consumer Control" and
+    /// "System Control" nodes alongside the node for the main keys.
+    pub fn with_siblings(self) -> Result<Vec<Self>> {
+        let others: Vec<Self> = Self::obtain_device_list()?;
+        let paths: Vec<&Path> = others.iter().map(|item| item.path.as_path()).collect();
+        let siblings = match sibling_paths(Path::new(SYSFS), &self.path, &paths) {
+            Some(siblings) => siblings,
+            None => {
+                log::warn!(
+                    "{} has no physical sysfs parent; not grouping siblings",
+                    self.path.display()
+                );
+                return Ok(vec![self]);
+            }
+        };
+
+        let mut devices: Vec<Self> = others
+            .into_iter()
+            .filter(|item| siblings.contains(&item.path))
+            .collect();
+        devices.insert(0, self);
+        Ok(devices)
+    }
+
     fn obtain_device_list() -> Result<Vec<DeviceInfo>> {
         let mut devices = vec![];
         for entry in std::fs::read_dir("/dev/input")? {
@@ -101,7 +127,7 @@ impl DeviceInfo {
     }
 }
 
-fn event_number_from_path(path: &PathBuf) -> u32 {
+fn event_number_from_path(path: &Path) -> u32 {
     match path.to_str() {
         Some(s) => match s.rfind("event") {
             Some(idx) => s[idx + 5..].parse().unwrap_or(0),
@@ -111,6 +137,65 @@ fn event_number_from_path(path: &PathBuf) -> u32 {
     }
 }
 
+const SYSFS: &str = "/sys";
+
+/// Returns those of the `candidates` event nodes that belong to the
+/// same device as the event node at `path`, given the sysfs tree
+/// mounted at `sysfs`.  Returns None if `path` has no physical parent
+/// to group by.
+fn sibling_paths(sysfs: &Path, path: &Path, candidates: &[&Path]) -> Option<Vec<PathBuf>> {
+    let parent = sysfs_parent(sysfs, path)?;
+    let usb = usb_device(&parent);
+    Some(
+        candidates
+            .iter()
+            .filter(|item| **item != path)
+            .filter(|item| match sysfs_parent(sysfs, item) {
+                Some(item_parent) => {
+                    item_parent == parent || (usb.is_some() && usb_device(&item_parent) == usb)
+                }
+                None => false,
+            })
+            .map(|item| item.to_path_buf())
+            .collect(),
+    )
+}
+
+/// Resolves the sysfs device that owns the input device behind the
+/// `/dev/input/eventN` node at `path`.  That is the HID device, or
+/// the serio port for a PS/2 keyboard.  Devices created by software,
+/// such as uinput devices, all share `/sys/devices/virtual`, so they
+/// have no parent to group by.
+fn sysfs_parent(sysfs: &Path, path: &Path) -> Option<PathBuf> {
+    let node = path.file_name()?;
+    let input = sysfs
+        .join("class/input")
+        .join(node)
+        .join("device")
+        .canonicalize()
+        .ok()?;
+    // Input devices live in an `input` subdirectory of their parent
+    let mut parent = input.parent()?;
+    if parent.file_name().map(|n| n == "input").unwrap_or(false) {
+        parent = parent.parent()?;
+    }
+    let virtual_devices = sysfs.join("devices/virtual").canonicalize().ok();
+    if virtual_devices.is_some_and(|dir| parent.starts_with(dir)) {
+        return None;
+    }
+    Some(parent.to_path_buf())
+}
+
+/// Walks up from a sysfs device path to the enclosing USB device, if
+/// any.  A keyboard can expose its keys through several interfaces of
+/// the same USB device, so the interfaces themselves are not compared.
+fn usb_device(path: &Path) -> Option<PathBuf> {
+    let interface = path
+        .ancestors()
+        .find(|dir| dir.join("bInterfaceNumber").exists())?;
+    interface.parent().map(Path::to_path_buf)
+}
+
 pub fn list_devices() -> Result<()> {
     let devices = DeviceInfo::obtain_device_list()?;
     for item in &devices {
@@ -121,3 +206,97 @@ pub fn list_devices() -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Builds a sysfs tree with a USB keyboard that has its main keys
+    /// and its media keys on separate interfaces, a PS/2 keyboard and
+    /// two uinput devices
+    fn fake_sysfs(name: &str) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("evremap-sysfs-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        let usb = "devices/pci0000:00/0000:00:14.0/usb1/1-2";
+        let devices = [
+            (
+                "event3",
+                format!("{usb}/1-2:1.0/0003:046D:C31C.0001/input/input3"),
+            ),
+            (
+                "event4",
+                format!("{usb}/1-2:1.1/0003:046D:C31C.0002/input/input4"),
+            ),
+            (
+                "event5",
+                format!("{usb}/1-2:1.1/0003:046D:C31C.0002/input/input5"),
+            ),
+            (
+                "event0",
+                "devices/platform/i8042/serio0/input/input0".to_string(),
+            ),
+            ("event7", "devices/virtual/input/input7".to_string()),
+            ("event8", "devices/virtual/input/input8".to_string()),
+        ];
+        for (node, device) in &devices {
+            fs::create_dir_all(root.join(device)).unwrap();
+            let class = root.join("class/input").join(node);
+            fs::create_dir_all(&class).unwrap();
+            std::os::unix::fs::symlink(root.join(device), class.join("device")).unwrap();
+        }
+        for interface in ["1-2:1.0", "1-2:1.1"] {
+            fs::write(
+                root.join(usb).join(interface).join("bInterfaceNumber"),
+                "00",
+            )
+            .unwrap();
+        }
+        root
+    }
+
+    fn siblings(sysfs: &Path, node: &str) -> Option<Vec<String>> {
+        let nodes = ["event0", "event3", "event4", "event5", "event7", "event8"];
+        let paths: Vec<PathBuf> = nodes
+            .iter()
+            .map(|n| Path::new("/dev/input").join(n))
+            .collect();
+        let paths: Vec<&Path> = paths.iter().map(PathBuf::as_path).collect();
+        let found = sibling_paths(sysfs, &Path::new("/dev/input").join(node), &paths)?;
+        Some(
+            found
+                .iter()
+                .map(|p| p.file_name().unwrap().to_string_lossy().into_owned())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn siblings_share_a_usb_device_or_a_parent() {
+        let sysfs = fake_sysfs("siblings");
+        assert_eq!(
+            siblings(&sysfs, "event3"),
+            Some(vec!["event4".to_string(), "event5".to_string()])
+        );
+        assert_eq!(
+            siblings(&sysfs, "event5"),
+            Some(vec!["event3".to_string(), "event4".to_string()])
+        );
+        assert_eq!(siblings(&sysfs, "event0"), Some(vec![]));
+        fs::remove_dir_all(&sysfs).unwrap();
+    }
+
+    #[test]
+    fn virtual_devices_are_not_grouped() {
+        let sysfs = fake_sysfs("virtual");
+        assert_eq!(siblings(&sysfs, "event7"), None);
+        // Nor are they the siblings of a physical device
+        assert!(!siblings(&sysfs, "event0")
+            .unwrap()
+            .contains(&"event7".to_string()));
+        // A node without a sysfs entry has nothing to group by
+        assert_eq!(siblings(&sysfs, "event9"), None);
+        fs::remove_dir_all(&sysfs).unwrap();
+    }
+}
@@ -0,0 +1,590 @@
+use crate::keyexpr::{Definitions, KeyExpr};
+use crate::mapping::*;
+use crate::remapper::{
+    add_logical_modifiers, extra_keys, extra_modifiers, output_value, press_rank, ComputeObserver,
+};
+use crate::rules::{Keys, RuleSet};
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::path::Path;
+
+fn parse_key(name: &str) -> Result<KeyCodeWrapper> {
+    Ok(KeyCodeWrapper::try_from(name.to_string())?)
+}
+
+fn key_list<'a>(defs: &Definitions, keys: impl IntoIterator<Item = &'a KeyCode>) -> String {
+    let names: Vec<String> = keys.into_iter().map(|k| defs.code_name(k)).collect();
+    if names.is_empty() {
+        "(none)".to_string()
+    } else {
+        names.join(" + ")
+    }
+}
+
+/// The physical key to hold for an input `code`.  Side-agnostic
+/// modifiers are held using their left hand key.
+fn held_key(code: &KeyCode) -> Option<KeyCode> {
+    match logical_modifier_keys(code) {
+        Some([left, _]) => Some(left),
+        None if matches!(code, EventCode::EV_KEY(_)) => Some(*code),
+        None => None,
+    }
+}
+
+/// Modifiers first, as they are pressed, then by name so that the
+/// output is stable
+fn press_order(keys: &HashSet<KeyCode>, modifiers: &HashSet<KeyCode>) -> Vec<KeyCode> {
+    let mut keys: Vec<KeyCode> = keys.iter().cloned().collect();
+    keys.sort_by_key(|k| (press_rank(k, modifiers, &[]), code_name(k)));
+    keys
+}
+
+/// Describes the steps taken by `compute_keys`
+struct Explainer<'a> {
+    config: &'a MappingConfig,
+    /// The held keys, as modified by the DualRole mappings
+    held: HashSet<KeyCode>,
+    reported_dual_roles: bool,
+    out: &'a mut Vec<String>,
+}
+
+impl<'a> ComputeObserver for Explainer<'a> {
+    fn dual_role(&mut self, idx: usize, input: &[KeyCode], hold: &[KeyCode]) {
+        self.out.push(format!(
+            "  dual_role: {} is held, so it becomes {}  ({})",
+            key_list(&self.config.definitions, input),
+            key_list(&self.config.definitions, hold),
+            self.config.origins[idx]
+        ));
+        for k in input {
+            self.held.remove(k);
+        }
+        self.held.extend(hold.iter().cloned());
+    }
+
+    fn dual_role_unless(&mut self, idx: usize, input: &[KeyCode], unless: Keys) {
+        self.out.push(format!(
+            "  dual_role: {} was pressed while {} was held, so it is unchanged  ({})",
+            key_list(&self.config.definitions, input),
+            key_list(
+                &self.config.definitions,
+                &press_order(&unless.to_set(), &self.config.modifiers)
+            ),
+            self.config.origins[idx]
+        ));
+    }
+
+    fn remap_matched(&mut self, idx: usize, released: Keys) {
+        self.report_dual_roles();
+        if let Mapping::Remap {
+            input,
+            output,
+            ordered,
+            ..
+        } = &self.config.mappings[idx]
+        {
+            let modifiers = &self.config.modifiers;
+            let output: Vec<KeyCode> = match ordered {
+                true => output.iter().map(|k| k.code).collect(),
+                false => press_order(&output.iter().map(|k| k.code).collect(), modifiers),
+            };
+            self.out.push(format!(
+                "  matched:  {} -> {}  ({})",
+                key_list(
+                    &self.config.definitions,
+                    &press_order(&input.iter().map(|k| k.code).collect(), modifiers)
+                ),
+                key_list(&self.config.definitions, &output),
+                self.config.origins[idx]
+            ));
+            if !released.is_empty() {
+                self.out.push(format!(
+                    "            and releases {} while it applies",
+                    key_list(
+                        &self.config.definitions,
+                        &press_order(&released.to_set(), modifiers)
+                    )
+                ));
+            }
+        }
+    }
+
+    fn remap_skipped(&mut self, idx: usize, available: Keys) {
+        self.report_dual_roles();
+        let available = &available.to_set();
+        let (input, policy, unless) = match &self.config.mappings[idx] {
+            Mapping::Remap {
+                input,
+                modifiers,
+                unless,
+                ..
+            } => (input, modifiers, unless),
+            _ => return,
+        };
+        let codes = press_order(
+            &input.iter().map(|k| k.code).collect(),
+            &self.config.modifiers,
+        );
+        let codes: Vec<&KeyCode> = codes.iter().collect();
+        if !codes.iter().all(|k| self.held.contains(k)) {
+            // Rules whose keys are not all held are not interesting
+            return;
+        }
+        let consumed: Vec<&KeyCode> = codes
+            .iter()
+            .filter(|k| !available.contains(k))
+            .cloned()
+            .collect();
+        if consumed.is_empty() {
+            let unless: HashSet<KeyCode> = unless
+                .iter()
+                .filter(|k| available.contains(k))
+                .cloned()
+                .collect();
+            let extras: HashSet<KeyCode> =
+                extra_modifiers(input, available, &self.config.modifiers).collect();
+            let reason = if !unless.is_empty() {
+                format!(
+                    "{} is held and is listed in its `unless`",
+                    key_list(
+                        &self.config.definitions,
+                        &press_order(&unless, &self.config.modifiers)
+                    )
+                )
+            } else if *policy == ModifierPolicy::Exact && !extras.is_empty() {
+                format!(
+                    "{} is also held and its modifiers are \"exact\"",
+                    key_list(
+                        &self.config.definitions,
+                        &press_order(&extras, &self.config.modifiers)
+                    )
+                )
+            } else {
+                let extras: HashSet<KeyCode> = extra_keys(input, available).collect();
+                format!(
+                    "{} is also held and the rule is \"exact\"",
+                    key_list(
+                        &self.config.definitions,
+                        &press_order(&extras, &self.config.modifiers)
+                    )
+                )
+            };
+            self.out.push(format!(
+                "  skipped:  {}, because {}  ({})",
+                key_list(&self.config.definitions, codes.iter().cloned()),
+                reason,
+                self.config.origins[idx]
+            ));
+            return;
+        }
+        self.out.push(format!(
+            "  shadowed: {}, because {} was consumed by an earlier rule  ({})",
+            key_list(&self.config.definitions, codes.iter().cloned()),
+            key_list(&self.config.definitions, consumed),
+            self.config.origins[idx]
+        ));
+    }
+}
+
+impl<'a> Explainer<'a> {
+    fn report_dual_roles(&mut self) {
+        if !self.reported_dual_roles {
+            self.reported_dual_roles = true;
+            add_logical_modifiers(&mut self.held);
+            self.out.push(format!(
+                "Keys seen by the remap rules: {}",
+                key_list(
+                    &self.config.definitions,
+                    &press_order(&self.held, &self.config.modifiers)
+                )
+            ));
+        }
+    }
+}
+
+/// Describe, line by line in `out`, the steps that the remapper takes
+/// when `keys` are held, and optionally when an additional REL or ABS
+/// `event` is received
+fn explain_keys(
+    config: &MappingConfig,
+    keys: &[String],
+    event: Option<(&str, i32)>,
+    out: &mut Vec<String>,
+) -> Result<()> {
+    // The keys are pressed in the order that they are given
+    let mut held: Vec<KeyCode> = vec![];
+    for expr in keys {
+        let expr = KeyExpr::parse_with(expr, &config.definitions)?;
+        for key in expr.0 {
+            let key = held_key(&key.code).unwrap_or(key.code);
+            if !held.contains(&key) {
+                held.push(key);
+            }
+        }
+    }
+    out.push(format!(
+        "Held keys: {}",
+        key_list(&config.definitions, &held)
+    ));
+
+    let rules = RuleSet::new(config.mappings.clone(), config.modifiers.clone())?;
+    let mut explainer = Explainer {
+        config,
+        held: held.iter().cloned().collect(),
+        reported_dual_roles: false,
+        out,
+    };
+    rules.compute_keys(&held, &mut explainer);
+    explainer.report_dual_roles();
+    out.push(format!(
+        "Output keys, in the order that they are pressed: {}",
+        key_list(&config.definitions, &rules.compute_keys_in_order(&held))
+    ));
+
+    for (map, origin) in config.mappings.iter().zip(&config.origins) {
+        if let Mapping::DualRole { input, tap, .. } = map {
+            if held.len() == input.len() && input.iter().all(|k| held.contains(k)) {
+                out.push(format!(
+                    "Tapping {} on its own emits {}  ({})",
+                    key_list(&config.definitions, input),
+                    key_list(&config.definitions, tap),
+                    origin
+                ));
+            }
+        }
+    }
+
+    if let Some((name, value)) = event {
+        let code = parse_key(name)?.code;
+        match rules.lookup_mapping(&held, code, value) {
+            Some(idx) => match &config.mappings[idx] {
+                Mapping::Remap { input, output, .. } => {
+                    let matched = input.iter().find(|i| {
+                        i.code == code
+                            && (i.scale == 0 || i.scale.is_negative() == value.is_negative())
+                    });
+                    out.push(format!(
+                        "Event {} {} matches {}",
+                        code_name(&code),
+                        value,
+                        config.origins[idx]
+                    ));
+                    if let Some(matched) = matched {
+                        for o in output {
+                            match o.code {
+                                EventCode::EV_KEY(_) => {
+                                    out.push(format!("  emits a tap of {}", code_name(&o.code)))
+                                }
+                                _ => out.push(format!(
+                                    "  emits {} {}",
+                                    code_name(&o.code),
+                                    output_value(matched, o, value)
+                                )),
+                            }
+                        }
+                    }
+                }
+                Mapping::DualRole { .. } => {
+                    out.push(format!(
+                        "Event {} {} matches {}",
+                        code_name(&code),
+                        value,
+                        config.origins[idx]
+                    ));
+                }
+            },
+            None => out.push(format!(
+                "Event {} {} passes through unchanged",
+                code_name(&code),
+                value
+            )),
+        }
+    }
+
+    Ok(())
+}
+
+/// Describe, line by line in `out`, the ways in which `target` can be
+/// produced
+fn explain_produces(config: &MappingConfig, target: &str, out: &mut Vec<String>) -> Result<()> {
+    let target = parse_key(target)?.code;
+    let rules = RuleSet::new(config.mappings.clone(), config.modifiers.clone())?;
+    let mut found = false;
+
+    if !matches!(target, EventCode::EV_KEY(_))
+        && [1, -1]
+            .iter()
+            .any(|&value| rules.lookup_mapping(&[], target, value).is_none())
+    {
+        out.push(format!("{} event  (passed through)", code_name(&target)));
+        found = true;
+    }
+
+    let mut report = |held: &[KeyCode], how: String| {
+        // Virtual modifiers and scancodes cannot be held directly
+        if !held.iter().all(|k| matches!(k, EventCode::EV_KEY(_))) {
+            return;
+        }
+        let held_set: HashSet<KeyCode> = held.iter().cloned().collect();
+        if rules.compute_keys(held, &mut ()).contains(&target) {
+            out.push(format!(
+                "hold {}  ({})",
+                key_list(
+                    &config.definitions,
+                    &press_order(&held_set, &config.modifiers)
+                ),
+                how
+            ));
+            found = true;
+        }
+    };
+
+    if matches!(target, EventCode::EV_KEY(_)) {
+        report(&[target], "passed through".to_string());
+    }
+
+    let dual_roles: Vec<(&Vec<KeyCode>, &Vec<KeyCode>)> = config
+        .mappings
+        .iter()
+        .filter_map(|map| match map {
+            Mapping::DualRole { input, hold, .. } => Some((input, hold)),
+            _ => None,
+        })
+        .collect();
+
+    for (map, origin) in config.mappings.iter().zip(&config.origins) {
+        match map {
+            Mapping::DualRole { input, hold, .. } => {
+                if hold.contains(&target) {
+                    report(input, origin.to_string());
+                }
+            }
+            Mapping::Remap { input, output, .. } => {
+                if !output.iter().any(|o| o.code == target) {
+                    continue;
+                }
+                if !input.iter().all(|i| is_key_code(&i.code)) {
+                    continue;
+                }
+                let keys: Vec<KeyCode> = input
+                    .iter()
+                    .map(|i| held_key(&i.code).unwrap_or(i.code))
+                    .collect();
+                report(&keys, origin.to_string());
+                // The same chord may also be reached via the hold
+                // function of a DualRole key
+                for (dual_input, hold) in &dual_roles {
+                    if keys.iter().any(|k| hold.contains(k)) {
+                        let mut via_dual: Vec<KeyCode> =
+                            keys.iter().filter(|k| !hold.contains(k)).cloned().collect();
+                        via_dual.extend(dual_input.iter().cloned());
+                        report(&via_dual, origin.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    for (idx, (map, origin)) in config.mappings.iter().zip(&config.origins).enumerate() {
+        match map {
+            Mapping::DualRole { input, tap, .. } if tap.contains(&target) => {
+                out.push(format!(
+                    "tap {}  ({})",
+                    key_list(&config.definitions, input),
+                    origin
+                ));
+                found = true;
+            }
+            Mapping::Remap { input, output, .. } if output.iter().any(|o| o.code == target) => {
+                let (events, held): (Vec<&KeyCodeWrapper>, Vec<&KeyCodeWrapper>) =
+                    input.iter().partition(|i| !is_key_code(&i.code));
+                for event in events {
+                    let direction = match event.scale {
+                        0 => "",
+                        n if n.is_negative() => " (negative)",
+                        _ => " (positive)",
+                    };
+                    let held: Vec<KeyCode> = held.iter().map(|k| k.code).collect();
+                    let value = if event.scale.is_negative() { -1 } else { 1 };
+                    let lookup = rules.lookup_mapping(&held, event.code, value);
+                    if lookup == Some(idx) {
+                        if held.is_empty() {
+                            out.push(format!(
+                                "{}{} event  ({})",
+                                code_name(&event.code),
+                                direction,
+                                origin
+                            ));
+                        } else {
+                            out.push(format!(
+                                "{}{} event while holding {}  ({})",
+                                code_name(&event.code),
+                                direction,
+                                key_list(&config.definitions, &held),
+                                origin
+                            ));
+                        }
+                        found = true;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if !found {
+        out.push(format!("No input produces {}", code_name(&target)));
+    }
+    Ok(())
+}
+
+pub fn explain(
+    config_file: &Path,
+    keys: &[String],
+    event: Option<(&str, i32)>,
+    produces: Option<&str>,
+) -> Result<()> {
+    let config = MappingConfig::from_file(config_file).context(format!(
+        "loading MappingConfig from {}",
+        config_file.display()
+    ))?;
+    let mut out = vec![];
+    let result = match produces {
+        Some(target) => explain_produces(&config, target, &mut out),
+        None => explain_keys(&config, keys, event, &mut out),
+    };
+    for line in out {
+        println!("{}", line);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [[dual_role]]
+        input = "CAPSLOCK"
+        hold = ["LEFTCTRL"]
+        tap = ["ESC"]
+
+        [[remap]]
+        input = ["LEFTCTRL", "H"]
+        output = ["LEFT"]
+
+        [[remap]]
+        input = ["LEFTCTRL", "J"]
+        output = ["DOWN"]
+        modifiers = "exact"
+
+        [[remap]]
+        input = ["LEFTALT", "REL_WHEEL"]
+        output = ["REL_HWHEEL"]
+    "#;
+
+    /// The lines that explain `keys`, `event` or `produces`, with the
+    /// path of the configuration file shortened to `config`
+    fn explain(
+        name: &str,
+        keys: &[&str],
+        event: Option<(&str, i32)>,
+        produces: Option<&str>,
+    ) -> Vec<String> {
+        let config = MappingConfig::from_toml(name, CONFIG);
+        let keys: Vec<String> = keys.iter().map(|k| k.to_string()).collect();
+        let mut out = vec![];
+        match produces {
+            Some(target) => explain_produces(&config, target, &mut out).unwrap(),
+            None => explain_keys(&config, &keys, event, &mut out).unwrap(),
+        }
+        let file = config.origins[0].file.display().to_string();
+        out.iter()
+            .map(|line| line.replace(&file, "config"))
+            .collect()
+    }
+
+    #[test]
+    fn held_keys() {
+        assert_eq!(
+            explain("explain-held", &["capslock", "h"], None, None),
+            [
+                "Held keys: KEY_CAPSLOCK + KEY_H",
+                "  dual_role: KEY_CAPSLOCK is held, so it becomes KEY_LEFTCTRL  (config: dual_role #1)",
+                "Keys seen by the remap rules: CTRL + KEY_LEFTCTRL + KEY_H",
+                "  matched:  KEY_LEFTCTRL + KEY_H -> KEY_LEFT  (config: remap #1)",
+                "Output keys, in the order that they are pressed: KEY_LEFT",
+            ]
+        );
+    }
+
+    #[test]
+    fn skipped_rules_say_why() {
+        assert_eq!(
+            explain("explain-skipped", &["lctrl", "lshift", "j"], None, None),
+            [
+                "Held keys: KEY_LEFTCTRL + KEY_LEFTSHIFT + KEY_J",
+                "Keys seen by the remap rules: CTRL + KEY_LEFTCTRL + KEY_LEFTSHIFT + SHIFT + KEY_J",
+                "  skipped:  KEY_LEFTCTRL + KEY_J, because KEY_LEFTSHIFT is also held and its modifiers are \"exact\"  (config: remap #2)",
+                "Output keys, in the order that they are pressed: KEY_LEFTCTRL + KEY_LEFTSHIFT + KEY_J",
+            ]
+        );
+    }
+
+    #[test]
+    fn tapping_a_dual_role_key() {
+        assert_eq!(
+            explain("explain-tap", &["capslock"], None, None)
+                .last()
+                .unwrap(),
+            "Tapping KEY_CAPSLOCK on its own emits KEY_ESC  (config: dual_role #1)"
+        );
+    }
+
+    #[test]
+    fn events() {
+        let out = explain("explain-event", &["alt"], Some(("REL_WHEEL", -1)), None);
+        assert_eq!(
+            out[out.len() - 2..],
+            [
+                "Event REL_WHEEL -1 matches config: remap #3",
+                "  emits REL_HWHEEL -1",
+            ]
+        );
+        let out = explain("explain-unmapped", &[], Some(("REL_WHEEL", 1)), None);
+        assert_eq!(
+            out.last().unwrap(),
+            "Event REL_WHEEL 1 passes through unchanged"
+        );
+    }
+
+    #[test]
+    fn produces() {
+        assert_eq!(
+            explain("explain-left", &[], None, Some("KEY_LEFT")),
+            [
+                "hold KEY_LEFT  (passed through)",
+                "hold KEY_LEFTCTRL + KEY_H  (config: remap #1)",
+                "hold KEY_CAPSLOCK + KEY_H  (config: remap #1)",
+            ]
+        );
+        assert_eq!(
+            explain("explain-esc", &[], None, Some("ESC")),
+            [
+                "hold KEY_ESC  (passed through)",
+                "tap KEY_CAPSLOCK  (config: dual_role #1)",
+            ]
+        );
+        assert_eq!(
+            explain("explain-hwheel", &[], None, Some("REL_HWHEEL")),
+            [
+                "REL_HWHEEL event  (passed through)",
+                "REL_WHEEL event while holding KEY_LEFTALT  (config: remap #3)",
+            ]
+        );
+        assert_eq!(
+            explain("explain-nothing", &[], None, Some("KEY_F13")),
+            ["hold KEY_F13  (passed through)"]
+        );
+    }
+}
@@ -0,0 +1,606 @@
+use crate::mapping::{
+    code_name, logical_modifier_code, logical_modifier_names, parse_code_number, virtual_code,
+    virtual_index, ConfigError, KeyCode, KeyCodeWrapper,
+};
+use evdev_rs::enums::{EventCode, EventType};
+use std::collections::HashMap;
+
+/// One or more keys written as a single string.  In addition to the
+/// `KEY_XXX` names printed by `evremap list-keys`, this accepts:
+///
+/// * Chords joined by `+`, eg: `alt+left` or `ctrl+shift+F5`
+/// * Emacs style modifier prefixes, eg: `C-S-x`.  `C` is ctrl, `S` is
+///   shift, `M` and `A` are alt, `s` is super and `H` is hyper.
+/// * Lowercase names without the `KEY_` prefix, eg: `f5` or `volumeup`
+/// * Common aliases such as `esc`, `enter` and `super`
+/// * The side-agnostic modifiers `CTRL`, `SHIFT`, `ALT` and `META`, and
+///   the virtual modifiers of the `Definitions` that are in effect
+/// * `btn:left` for `BTN_LEFT`, `scan:0x70039` for `SCAN_0x70039`, and `wheel-up`/`wheel-down`/`wheel-left`/
+///   `wheel-right` for the directions of the scroll wheels
+///
+/// A `KeyExpr` displays as a `+` separated list of `KEY_XXX` names,
+/// which parses back into the same keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyExpr(pub Vec<KeyCodeWrapper>);
+
+/// Names that are defined by the configuration file, which can be
+/// used in key expressions
+#[derive(Debug, Clone)]
+pub struct Definitions {
+    /// The virtual modifiers, by upper case name, along with the keys
+    /// that make up each composite modifier.  An internal modifier has
+    /// no keys; it is only ever held as the output of another rule.
+    pub virtual_modifiers: HashMap<String, Vec<KeyCodeWrapper>>,
+    /// The named groups of keys, which are referred to as `@name`
+    pub groups: HashMap<String, Vec<KeyCodeWrapper>>,
+    /// The names of the virtual modifier codes, indexed by code: the
+    /// logical modifiers followed by the internal modifiers
+    virtual_names: Vec<String>,
+}
+
+impl Default for Definitions {
+    /// The built-in HYPER and MEH composite modifiers
+    fn default() -> Self {
+        let logical = |names: &[&str]| {
+            names
+                .iter()
+                .filter_map(|name| logical_modifier_code(name))
+                .map(|code| KeyCodeWrapper { code, scale: 1 })
+                .collect()
+        };
+        let mut defs = Self::empty();
+        defs.virtual_modifiers.insert(
+            "HYPER".to_string(),
+            logical(&["CTRL", "ALT", "SHIFT", "META"]),
+        );
+        defs.virtual_modifiers
+            .insert("MEH".to_string(), logical(&["CTRL", "ALT", "SHIFT"]));
+        defs
+    }
+}
+
+impl Definitions {
+    /// Only the names that are built into the key expressions
+    pub fn empty() -> Self {
+        Self {
+            virtual_modifiers: HashMap::new(),
+            groups: HashMap::new(),
+            virtual_names: logical_modifier_names().map(str::to_string).collect(),
+        }
+    }
+
+    /// Define the virtual modifier `name` as `keys`, allocating a code
+    /// for it if it is an internal modifier
+    pub fn define_virtual_modifier(&mut self, name: String, keys: Vec<KeyCodeWrapper>) {
+        if keys.is_empty() && !self.virtual_names.contains(&name) {
+            self.virtual_names.push(name.clone());
+        }
+        self.virtual_modifiers.insert(name, keys);
+    }
+
+    /// Returns the code of the internal modifier `name`
+    fn internal_code(&self, name: &str) -> Option<KeyCode> {
+        self.virtual_names
+            .iter()
+            .position(|known| known == name)
+            .map(virtual_code)
+    }
+
+    /// The name of `code`, including those of the internal modifiers
+    pub fn code_name(&self, code: &KeyCode) -> String {
+        match virtual_index(code).and_then(|idx| self.virtual_names.get(idx)) {
+            Some(name) => name.clone(),
+            None => code_name(code),
+        }
+    }
+}
+
+/// Names that are accepted in place of the `KEY_XXX` names, and the keys
+/// that they stand for.  The unqualified modifiers are side-agnostic.
+const ALIASES: &[(&str, &[&str])] = &[
+    ("esc", &["KEY_ESC"]),
+    ("escape", &["KEY_ESC"]),
+    ("enter", &["KEY_ENTER"]),
+    ("return", &["KEY_ENTER"]),
+    ("ret", &["KEY_ENTER"]),
+    ("spc", &["KEY_SPACE"]),
+    ("bs", &["KEY_BACKSPACE"]),
+    ("del", &["KEY_DELETE"]),
+    ("ins", &["KEY_INSERT"]),
+    ("pgup", &["KEY_PAGEUP"]),
+    ("pgdn", &["KEY_PAGEDOWN"]),
+    ("pgdown", &["KEY_PAGEDOWN"]),
+    ("caps", &["KEY_CAPSLOCK"]),
+    ("menu", &["KEY_COMPOSE"]),
+    ("control", &["CTRL"]),
+    ("lctrl", &["KEY_LEFTCTRL"]),
+    ("rctrl", &["KEY_RIGHTCTRL"]),
+    ("lshift", &["KEY_LEFTSHIFT"]),
+    ("rshift", &["KEY_RIGHTSHIFT"]),
+    ("opt", &["ALT"]),
+    ("option", &["ALT"]),
+    ("lalt", &["KEY_LEFTALT"]),
+    ("ralt", &["KEY_RIGHTALT"]),
+    ("altgr", &["KEY_RIGHTALT"]),
+    ("super", &["META"]),
+    ("win", &["META"]),
+    ("cmd", &["META"]),
+    ("lsuper", &["KEY_LEFTMETA"]),
+    ("rsuper", &["KEY_RIGHTMETA"]),
+    ("wheel-up", &["REL_WHEEL+"]),
+    ("wheel-down", &["REL_WHEEL-"]),
+    ("wheel-left", &["REL_HWHEEL-"]),
+    ("wheel-right", &["REL_HWHEEL+"]),
+];
+
+/// The alias for each of the emacs style modifier prefixes
+fn emacs_modifier(prefix: u8) -> Option<&'static str> {
+    match prefix {
+        b'C' => Some("ctrl"),
+        b'S' => Some("shift"),
+        b'M' | b'A' => Some("alt"),
+        b's' => Some("super"),
+        b'H' => Some("hyper"),
+        _ => None,
+    }
+}
+
+impl KeyExpr {
+    /// Parse `expr` using only the built-in definitions
+    pub fn parse(expr: &str) -> Result<Self, ConfigError> {
+        Self::parse_with(expr, &Definitions::default())
+    }
+
+    /// Parse `expr`, which may refer to the names in `defs`
+    pub fn parse_with(expr: &str, defs: &Definitions) -> Result<Self, ConfigError> {
+        let mut keys = vec![];
+        let mut rest = expr.trim();
+        while rest.len() > 2 && rest.as_bytes()[1] == b'-' {
+            match emacs_modifier(rest.as_bytes()[0]) {
+                Some(alias) => {
+                    keys.extend(parse_term(expr, alias, defs)?);
+                    rest = &rest[2..];
+                }
+                None => break,
+            }
+        }
+        let terms = split_chord(rest);
+        for (idx, term) in terms.iter().enumerate() {
+            let parsed = parse_term(expr, term, defs);
+            // `REL_X+abc` is a mistyped scale rather than a chord
+            // with an unknown key
+            if let (Err(ConfigError::InvalidKey { .. }), Some(axis)) =
+                (&parsed, idx.checked_sub(1).map(|prev| terms[prev]))
+            {
+                if is_axis_name(&axis.to_uppercase()) {
+                    return Err(ConfigError::InvalidScale {
+                        name: axis.to_string(),
+                        scale: format!("+{}", term),
+                    });
+                }
+            }
+            keys.extend(parsed?);
+        }
+        Ok(Self(keys))
+    }
+}
+
+impl std::fmt::Display for KeyExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (idx, key) in self.0.iter().enumerate() {
+            if idx > 0 {
+                write!(f, "+")?;
+            }
+            write!(f, "{}", key)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for KeyExpr {
+    type Err = ConfigError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+/// Returns true if `name` is an upper case `XXX_YYY` name or a numeric
+/// `XXX_0x123` name, optionally followed by a `+` or `-` direction and scale
+fn is_event_name(name: &str) -> bool {
+    let name = match name.rfind(['+', '-']) {
+        Some(pos) if name[pos + 1..].bytes().all(|b| b.is_ascii_digit()) => &name[..pos],
+        _ => name,
+    };
+    match name.split_once('_') {
+        Some((prefix, rest)) => {
+            !prefix.is_empty()
+                && prefix.bytes().all(|b| b.is_ascii_uppercase())
+                && (parse_code_number(rest).is_some()
+                    || rest
+                        .bytes()
+                        .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'_'))
+        }
+        None => false,
+    }
+}
+
+/// Returns true if `name` is a `REL_XXX` or `ABS_XXX` name with no scale
+fn is_axis_name(name: &str) -> bool {
+    (name.starts_with("REL_") || name.starts_with("ABS_"))
+        && is_event_name(name)
+        && !name.contains(['+', '-'])
+}
+
+/// Split a chord into its terms.  `+` separates the terms, except when it
+/// is the direction of a `REL_XXX` or `ABS_XXX` name, as in `REL_X+2`.
+fn split_chord(expr: &str) -> Vec<&str> {
+    let mut terms = vec![];
+    let mut rest = expr;
+    while let Some(pos) = rest.find('+') {
+        let term = &rest[..pos];
+        if is_event_name(term) && (term.starts_with("REL_") || term.starts_with("ABS_")) {
+            let after = &rest[pos + 1..];
+            let scale_len =
+                after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            let end = pos + 1 + scale_len;
+            if end == rest.len() {
+                break;
+            }
+            if rest[end..].starts_with('+') {
+                terms.push(&rest[..end]);
+                rest = &rest[end + 1..];
+                continue;
+            }
+        }
+        terms.push(term);
+        rest = &rest[pos + 1..];
+    }
+    terms.push(rest);
+    terms
+}
+
+/// Parse a single term of `expr`
+fn parse_term(
+    expr: &str,
+    term: &str,
+    defs: &Definitions,
+) -> Result<Vec<KeyCodeWrapper>, ConfigError> {
+    if term.is_empty() {
+        return Err(ConfigError::MissingKey {
+            expr: expr.to_string(),
+        });
+    }
+    let upper = term.to_uppercase();
+    if let Some(code) = logical_modifier_code(&upper).or_else(|| defs.internal_code(&upper)) {
+        return Ok(vec![KeyCodeWrapper { code, scale: 1 }]);
+    }
+    if let Some(keys) = defs.virtual_modifiers.get(&upper) {
+        return Ok(keys.clone());
+    }
+    if is_event_name(term) {
+        return Ok(vec![KeyCodeWrapper::from_name(term)?]);
+    }
+
+    let lower = term.to_lowercase();
+    if let Some((_, names)) = ALIASES.iter().find(|(alias, _)| *alias == lower) {
+        let mut keys = vec![];
+        for name in names.iter() {
+            keys.extend(parse_term(expr, name, defs)?);
+        }
+        return Ok(keys);
+    }
+
+    let candidates = if let Some(button) = lower.strip_prefix("btn:") {
+        vec![format!("BTN_{}", button.to_uppercase())]
+    } else if let Some(scan) = lower.strip_prefix("scan:") {
+        vec![format!("SCAN_{}", scan)]
+    } else {
+        vec![format!("KEY_{}", upper), upper]
+    };
+    candidates
+        .iter()
+        .filter(|name| is_event_name(name) && !name.contains(['+', '-']))
+        .find_map(|name| KeyCodeWrapper::from_name(name).ok())
+        .map(|key| vec![key])
+        .ok_or_else(|| match term.find(['+', '-']) {
+            Some(pos) if is_axis_name(&term[..pos].to_uppercase()) => ConfigError::InvalidScale {
+                name: term[..pos].to_string(),
+                scale: term[pos..].to_string(),
+            },
+            _ => ConfigError::invalid_key(term),
+        })
+}
+
+/// Returns true if `expr` names a group of keys rather than a chord:
+/// a `@name` reference, a range such as `KEY_F1..KEY_F12`, or a list
+/// of these separated by spaces or commas
+pub fn is_group_expr(expr: &str) -> bool {
+    expr.trim_start().starts_with('@') || expr.contains("..")
+}
+
+/// Parse a group of keys.  Each item of `expr` is a `@name` reference
+/// to one of the groups in `defs`, a range, or a single key.
+pub fn parse_group(expr: &str, defs: &Definitions) -> Result<Vec<KeyCodeWrapper>, ConfigError> {
+    let mut keys = vec![];
+    for item in expr.split([' ', '\t', ',']).filter(|item| !item.is_empty()) {
+        if let Some(name) = item.strip_prefix('@') {
+            match defs.groups.get(name) {
+                Some(members) => keys.extend(members.iter().cloned()),
+                None => {
+                    return Err(ConfigError::UnknownGroup {
+                        name: name.to_string(),
+                    })
+                }
+            }
+        } else if let Some((first, last)) = item.split_once("..") {
+            if first.is_empty() || last.is_empty() {
+                return Err(ConfigError::InvalidRange {
+                    expr: item.to_string(),
+                    reason: "a range needs a first and a last key".to_string(),
+                });
+            }
+            let first = single_key(first, defs)?;
+            let last = single_key(last, defs)?;
+            keys.extend(expand_range(item, &first, &last)?);
+        } else {
+            keys.push(single_key(item, defs)?);
+        }
+    }
+    if keys.is_empty() {
+        return Err(ConfigError::MissingKey {
+            expr: expr.to_string(),
+        });
+    }
+    Ok(keys)
+}
+
+fn single_key(expr: &str, defs: &Definitions) -> Result<KeyCodeWrapper, ConfigError> {
+    let mut keys = KeyExpr::parse_with(expr, defs)?.0;
+    if keys.len() != 1 {
+        return Err(ConfigError::NotSingleKey {
+            expr: expr.to_string(),
+            count: keys.len(),
+        });
+    }
+    Ok(keys.remove(0))
+}
+
+/// Splits a name such as `KEY_F12` or `KEY_A` into its prefix and the
+/// number or letter at its end
+fn split_ordinal(name: &str) -> Option<(&str, Result<u32, u8>)> {
+    if name.contains("0x") {
+        return None;
+    }
+    let prefix = name.trim_end_matches(|c: char| c.is_ascii_digit());
+    if prefix.len() < name.len() {
+        return name[prefix.len()..].parse().ok().map(|n| (prefix, Ok(n)));
+    }
+    match name.as_bytes() {
+        [.., b'_', letter] if letter.is_ascii_uppercase() => {
+            Some((&name[..name.len() - 1], Err(*letter)))
+        }
+        _ => None,
+    }
+}
+
+/// Expand the range `expr` from `first` to `last`.  When both ends are
+/// numbered or lettered names with the same prefix, such as
+/// `KEY_F1..KEY_F12` or `KEY_A..KEY_Z`, the keys are taken in the order
+/// of their names.  Otherwise they are taken in the order of the codes
+/// that the kernel assigns to them, so that `KEY_1..KEY_0` follows the
+/// number row.
+fn expand_range(
+    expr: &str,
+    first: &KeyCodeWrapper,
+    last: &KeyCodeWrapper,
+) -> Result<Vec<KeyCodeWrapper>, ConfigError> {
+    let invalid = |reason: &str| ConfigError::InvalidRange {
+        expr: expr.to_string(),
+        reason: reason.to_string(),
+    };
+    if !matches!(first.code, EventCode::EV_KEY(_)) || !matches!(last.code, EventCode::EV_KEY(_)) {
+        return Err(invalid("a range can only contain keys"));
+    }
+    let (first_name, last_name) = (code_name(&first.code), code_name(&last.code));
+    if let (Some((prefix, from)), Some((last_prefix, to))) =
+        (split_ordinal(&first_name), split_ordinal(&last_name))
+    {
+        let names: Option<Vec<String>> = match (from, to) {
+            (Ok(from), Ok(to)) if from < to => {
+                Some((from..=to).map(|n| format!("{}{}", prefix, n)).collect())
+            }
+            (Err(from), Err(to)) if from < to => Some(
+                (from..=to)
+                    .map(|c| format!("{}{}", prefix, c as char))
+                    .collect(),
+            ),
+            _ => None,
+        };
+        if let (Some(names), true) = (names, prefix == last_prefix) {
+            return names
+                .iter()
+                .map(|name| {
+                    KeyCodeWrapper::from_name(name)
+                        .map_err(|_| invalid(&format!("there is no key named {}", name)))
+                })
+                .collect();
+        }
+    }
+
+    let (_, from) = evdev_rs::util::event_code_to_int(&first.code);
+    let (_, to) = evdev_rs::util::event_code_to_int(&last.code);
+    if from > to {
+        return Err(invalid(&format!(
+            "{} comes after {}",
+            first_name, last_name
+        )));
+    }
+    Ok((from..=to)
+        .map(|code| evdev_rs::util::int_to_event_code(EventType::EV_KEY as u32, code))
+        .filter(|code| !matches!(code, EventCode::EV_UNK { .. }))
+        .map(|code| KeyCodeWrapper { code, scale: 1 })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev_rs::enums::EV_KEY;
+
+    fn key(code: EV_KEY) -> KeyCodeWrapper {
+        KeyCodeWrapper {
+            code: EventCode::EV_KEY(code),
+            scale: 1,
+        }
+    }
+
+    fn names(keys: &[KeyCodeWrapper]) -> Vec<String> {
+        keys.iter().map(|k| k.to_string()).collect()
+    }
+
+    #[test]
+    fn display_parses_back() {
+        for expr in [
+            "esc",
+            "ctrl+shift+F5",
+            "lctrl+altgr+del",
+            "super+spc",
+            "C-S-x",
+            "M-H-left",
+            "s-wheel-up",
+            "wheel-up+ctrl",
+            "REL_X-2+KEY_A",
+            "btn:left",
+            "scan:0x70039",
+            "KEY_0x2ff",
+        ] {
+            let parsed = KeyExpr::parse(expr).unwrap();
+            let shown = parsed.to_string();
+            assert_eq!(
+                KeyExpr::parse(&shown),
+                Ok(parsed),
+                "{} shown as {}",
+                expr,
+                shown
+            );
+        }
+    }
+
+    #[test]
+    fn group_display_parses_back() {
+        let mut defs = Definitions::default();
+        defs.groups.insert(
+            "arrows".to_string(),
+            parse_group("up down left right", &defs).unwrap(),
+        );
+        for expr in [
+            "KEY_F1..KEY_F3, esc",
+            "@arrows KEY_A..KEY_C",
+            "KEY_1..KEY_0",
+        ] {
+            let parsed = parse_group(expr, &defs).unwrap();
+            let shown = names(&parsed).join(" ");
+            assert_eq!(
+                parse_group(&shown, &defs),
+                Ok(parsed),
+                "{} shown as {}",
+                expr,
+                shown
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_expressions_are_errors() {
+        for expr in [
+            "",
+            "+",
+            "KEY_A+",
+            "+KEY_A",
+            "ctrl++a",
+            "C-",
+            "notakey",
+            "KEY_NOTAKEY",
+            "scan:xyz",
+            "btn:",
+            "KEY_0xzz",
+            "REL_X+abc",
+            "REL_X-abc",
+        ] {
+            assert!(KeyExpr::parse(expr).is_err(), "{} parsed", expr);
+        }
+        let defs = Definitions::default();
+        for expr in [
+            "",
+            "..",
+            "KEY_A..",
+            "..KEY_A",
+            "KEY_F12..KEY_F1",
+            "ctrl..KEY_A",
+            "KEY_A..REL_X",
+            "@missing",
+            "KEY_A+KEY_B..KEY_C",
+        ] {
+            assert!(parse_group(expr, &defs).is_err(), "{} parsed", expr);
+        }
+    }
+
+    #[test]
+    fn mistyped_scale_is_a_scale_error() {
+        for (expr, scale) in [
+            ("REL_X+abc", "+abc"),
+            ("REL_X-abc", "-abc"),
+            ("REL_X+1a", "+1a"),
+        ] {
+            assert_eq!(
+                KeyExpr::parse(expr),
+                Err(ConfigError::InvalidScale {
+                    name: "REL_X".to_string(),
+                    scale: scale.to_string(),
+                })
+            );
+        }
+        // Once the scale is complete, what follows is another key
+        assert!(matches!(
+            KeyExpr::parse("REL_X+2+abc"),
+            Err(ConfigError::InvalidKey { .. })
+        ));
+        assert_eq!(
+            KeyExpr::parse("REL_X+a").unwrap().0,
+            vec![
+                KeyCodeWrapper::from_name("REL_X").unwrap(),
+                key(EV_KEY::KEY_A)
+            ]
+        );
+    }
+
+    #[test]
+    fn ranges_follow_names_or_codes() {
+        let range = |first: EV_KEY, last: EV_KEY| {
+            expand_range("range", &key(first), &key(last)).map(|keys| names(&keys))
+        };
+        assert_eq!(
+            range(EV_KEY::KEY_F9, EV_KEY::KEY_F12).unwrap(),
+            ["KEY_F9", "KEY_F10", "KEY_F11", "KEY_F12"]
+        );
+        assert_eq!(
+            range(EV_KEY::KEY_A, EV_KEY::KEY_D).unwrap(),
+            ["KEY_A", "KEY_B", "KEY_C", "KEY_D"]
+        );
+        // The number row is in code order, which puts KEY_0 last
+        let digits = range(EV_KEY::KEY_1, EV_KEY::KEY_0).unwrap();
+        assert_eq!(digits.len(), 10);
+        assert_eq!((digits[0].as_str(), digits[9].as_str()), ("KEY_1", "KEY_0"));
+        // Reversed, by name and by code
+        assert!(range(EV_KEY::KEY_F12, EV_KEY::KEY_F1).is_err());
+        assert!(range(EV_KEY::KEY_D, EV_KEY::KEY_A).is_err());
+        // With different prefixes, the codes decide
+        let mixed = range(EV_KEY::KEY_A, EV_KEY::KEY_F1).unwrap();
+        assert_eq!(mixed.first().map(String::as_str), Some("KEY_A"));
+        assert_eq!(mixed.last().map(String::as_str), Some("KEY_F1"));
+        assert_eq!(mixed.len(), 30);
+        assert!(range(EV_KEY::KEY_KP1, EV_KEY::KEY_3).is_err());
+        let rel = KeyCodeWrapper::from_name("REL_X").unwrap();
+        assert!(expand_range("range", &rel, &key(EV_KEY::KEY_A)).is_err());
+    }
+}
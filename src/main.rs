@@ -3,22 +3,30 @@ use std::time::Duration;
 
 use anyhow::{Context, Result};
 use clap::Parser;
-use evdev_rs::enums::{EV_ABS, EV_KEY, EV_REL};
 
 use crate::mapping::*;
-use crate::remapper::InputMapper;
+use crate::reload::ConfigWatcher;
+use crate::remapper::{InputMapper, StopReason};
 
+mod check;
 mod deviceinfo;
+mod explain;
+mod keyexpr;
 mod mapping;
+mod privileges;
+mod reload;
 mod remapper;
+mod rules;
+mod runtime;
+mod sandbox;
+
+/// The exit status after the kill switch stops the remapper; see
+/// `RestartPreventExitStatus` in evremap.service
+const KILL_SWITCH_EXIT_STATUS: i32 = 3;
 
 /// Remap libinput evdev keyboard inputs
 #[derive(Debug, Parser)]
-#[command(
-    name = "evremap",
-    about,
-    author = "Wez Furlong"
-)]
+#[command(name = "evremap", about, author = "Wez Furlong")]
 enum Opt {
     /// Rather than running the remapper, list currently available devices.
     /// This is helpful to check their names when setting up the initial
@@ -28,6 +36,44 @@ enum Opt {
     /// Show a list of possible KEY_XXX values
     ListKeys,
 
+    /// Load a remapper config and report any problems with it, such as
+    /// rules that can never fire, without accessing any devices.
+    /// Exits with a non-zero status if problems were found.
+    Check {
+        /// Specify the configuration file to be checked
+        #[arg(name = "CONFIG-FILE")]
+        config_file: PathBuf,
+
+        /// How to report the problems
+        #[arg(short, long, value_enum, default_value_t = check::OutputFormat::Text)]
+        format: check::OutputFormat,
+    },
+
+    /// Load a remapper config and show step by step how it applies to
+    /// a set of held keys, without accessing any devices
+    Explain {
+        /// Specify the configuration file to be loaded
+        #[arg(name = "CONFIG-FILE")]
+        config_file: PathBuf,
+
+        /// The keys that are held, eg: KEY_LEFTALT KEY_LEFT or alt+left
+        keys: Vec<String>,
+
+        /// Also show what happens when a REL_XXX or ABS_XXX event
+        /// is received while the keys are held
+        #[arg(short, long)]
+        event: Option<String>,
+
+        /// The value of the --event
+        #[arg(short, long, default_value_t = 1, allow_hyphen_values = true)]
+        value: i32,
+
+        /// Rather than explaining a set of held keys, show the inputs
+        /// that produce this key
+        #[arg(short, long, conflicts_with = "keys")]
+        produces: Option<String>,
+    },
+
     /// Load a remapper config and run the remapper.
     /// This usually requires running as root to obtain exclusive access
     /// to the input devices.
@@ -36,25 +82,27 @@ enum Opt {
         #[arg(name = "CONFIG-FILE")]
         config_file: PathBuf,
 
-        /// Number of seconds for user to release keys on startup
-        #[arg(short, long, default_value = "2")]
+        /// Number of seconds to wait before starting.  The remapper
+        /// always waits for any held keys to be released before it
+        /// grabs the input devices.
+        #[arg(short, long, default_value = "0")]
         delay: f64,
+
+        /// Number of seconds to wait for held keys to be released before
+        /// grabbing the input devices anyway.  Keys that are still held
+        /// are ignored until they are released.
+        #[arg(long, default_value = "5")]
+        keys_up_timeout: f64,
+
+        /// Reload the configuration whenever the file is changed.
+        /// The configuration is always reloaded on SIGHUP.
+        #[arg(short, long)]
+        watch: bool,
     },
 }
 
 pub fn list_keys() -> Result<()> {
-    let mut keys: Vec<String> = EventCode::EV_KEY(EV_KEY::KEY_RESERVED)
-        .iter()
-        .chain(EventCode::EV_REL(EV_REL::REL_X).iter()).chain(EventCode::EV_ABS(EV_ABS::ABS_X).iter())
-        .filter_map(|code| match code {
-            EventCode::EV_KEY(_) => Some(format!("{}", code)),
-            EventCode::EV_REL(_) => Some(format!("{}", code)),
-            EventCode::EV_ABS(_) => Some(format!("{}", code)),
-            _ => None,
-        })
-        .collect();
-    keys.sort();
-    for key in keys {
+    for key in mapping::known_key_names() {
         println!("{}", key);
     }
     Ok(())
@@ -77,22 +125,78 @@ fn main() -> Result<()> {
     match opt {
         Opt::ListDevices => deviceinfo::list_devices(),
         Opt::ListKeys => list_keys(),
-        Opt::Remap { config_file, delay } => {
+        Opt::Check {
+            config_file,
+            format,
+        } => {
+            if !check::check_config(&config_file, format)? {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        Opt::Explain {
+            config_file,
+            keys,
+            event,
+            value,
+            produces,
+        } => explain::explain(
+            &config_file,
+            &keys,
+            event.as_deref().map(|e| (e, value)),
+            produces.as_deref(),
+        ),
+        Opt::Remap {
+            config_file,
+            delay,
+            keys_up_timeout,
+            watch,
+        } => {
             let mapping_config = MappingConfig::from_file(&config_file).context(format!(
                 "loading MappingConfig from {}",
                 config_file.display()
             ))?;
 
-            log::warn!("Short delay: release any keys now!");
-            std::thread::sleep(Duration::from_secs_f64(delay));
+            if delay > 0.0 {
+                log::warn!("Short delay: release any keys now!");
+                std::thread::sleep(Duration::from_secs_f64(delay));
+            }
 
             let device_info = deviceinfo::DeviceInfo::with_name(
                 &mapping_config.device_name,
                 mapping_config.phys.as_deref(),
             )?;
 
-            let mut mapper = InputMapper::create_mapper(device_info.path, mapping_config.mappings)?;
-            mapper.run_mapper()
+            let devices = if mapping_config.group_siblings {
+                device_info.with_siblings()?
+            } else {
+                vec![device_info]
+            };
+            for sibling in &devices[1..] {
+                log::info!(
+                    "Grouping sibling `{}` at {}",
+                    sibling.name,
+                    sibling.path.display()
+                );
+            }
+            let paths: Vec<_> = devices.into_iter().map(|dev| dev.path).collect();
+
+            let watcher = ConfigWatcher::new(&config_file, &mapping_config, watch)?;
+
+            let mut mapper = InputMapper::create_mapper(
+                &paths,
+                mapping_config,
+                Duration::try_from_secs_f64(keys_up_timeout)
+                    .context("the --keys-up-timeout must be a number of seconds")?,
+            )?;
+            mapper.watch_config(watcher);
+            if mapper.run_mapper()? == StopReason::KillSwitch {
+                // The keys have been released and the inputs ungrabbed;
+                // the exit status tells a service manager not to start
+                // the remapper again with the same configuration
+                std::process::exit(KILL_SWITCH_EXIT_STATUS);
+            }
+            Ok(())
         }
     }
 }
@@ -1,53 +1,680 @@
+use crate::keyexpr::{is_group_expr, parse_group, Definitions, KeyExpr};
 use anyhow::Context;
 pub use evdev_rs::enums::{EventCode, EventCode as KeyCode, EventType};
+use evdev_rs::enums::{EV_ABS, EV_KEY, EV_REL};
 use serde::Deserialize;
-use std::collections::HashSet;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use thiserror::Error;
+use toml::Spanned;
 use std::hash::{Hash, Hasher};
 
 #[derive(Debug, Clone)]
 pub struct MappingConfig {
     pub device_name: String,
     pub phys: Option<String>,
+    pub group_siblings: bool,
     pub mappings: Vec<Mapping>,
+    /// Keys that count as modifiers in addition to the built-in ones
+    pub modifiers: HashSet<KeyCode>,
+    /// Where each of the `mappings` was defined
+    pub origins: Vec<RuleOrigin>,
+    /// Every file that contributed to this configuration
+    pub files: Vec<PathBuf>,
+    /// The directory that was checked for configuration fragments
+    pub fragment_dir: PathBuf,
+    /// The names defined by the configuration
+    pub definitions: Definitions,
+    /// How the remapper checks for stuck keys
+    pub watchdog: WatchdogConfig,
+    /// The chord that bypasses the mappings: the configured one, the
+    /// fallback one if none was configured, or None if it is disabled
+    pub kill_switch: Option<KillSwitch>,
+    /// How the remapper thread is scheduled
+    pub runtime: RuntimeConfig,
+    /// The user to switch to once the devices are open, if any
+    pub privileges: Option<PrivilegesConfig>,
+    /// How the remapper restricts itself once the devices are open,
+    /// if it does
+    pub sandbox: Option<SandboxConfig>,
+}
+
+/// A chord of physical keys that is recognized before any of the
+/// mappings are applied, so that it works however broken they are
+#[derive(Debug, Clone, PartialEq)]
+pub struct KillSwitch {
+    pub keys: Vec<KeyCode>,
+    pub action: KillSwitchAction,
+}
+
+impl KillSwitch {
+    /// The kill switch that is used when none is configured: both
+    /// SHIFT keys held together with ESC
+    pub fn fallback() -> Self {
+        Self {
+            keys: vec![
+                KeyCode::EV_KEY(EV_KEY::KEY_LEFTSHIFT),
+                KeyCode::EV_KEY(EV_KEY::KEY_RIGHTSHIFT),
+                KeyCode::EV_KEY(EV_KEY::KEY_ESC),
+            ],
+            action: KillSwitchAction::Passthrough,
+        }
+    }
+
+    /// Returns true if pressing `code` while `held` are held, which
+    /// includes `code`, completes this chord
+    pub fn pressed_by(&self, code: &KeyCode, held: &HashSet<KeyCode>) -> bool {
+        self.keys.contains(code) && self.keys.iter().all(|k| held.contains(k))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KillSwitchAction {
+    /// Toggle between applying the mappings and passing every event
+    /// through unchanged
+    #[default]
+    Passthrough,
+    /// Release everything, including the grab on the inputs, and stop
+    Exit,
+}
+
+/// The `[watchdog]` section, which controls how the remapper checks
+/// for keys that are held on the output device when they shouldn't be
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct WatchdogConfig {
+    /// After this many seconds without input events while keys are
+    /// held, release any output keys that the held input keys don't
+    /// explain.  0, the default, disables the check.
+    pub idle_timeout: u64,
+}
+
+/// The `[runtime]` section, which trades some of the machine's
+/// resources for lower and steadier latency
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    /// The scheduling policy of the remapper thread
+    pub scheduler: Scheduler,
+    /// The realtime priority used by the `fifo` and `rr` schedulers,
+    /// from 1 to 99; defaults to 50
+    pub priority: Option<u32>,
+    /// Lock the memory of the remapper so that it is never paged out
+    pub lock_memory: bool,
+    /// Only run the remapper thread on this CPU
+    pub cpu: Option<usize>,
+    /// Every this many seconds, log how long it took for the input
+    /// events to be written to the output device.  0 disables it.
+    pub latency_log_interval: u64,
+}
+
+/// The `[privileges]` section, which names the unprivileged user that
+/// the remapper switches to once it has opened and grabbed the devices
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PrivilegesConfig {
+    pub user: String,
+    /// Defaults to the primary group of the `user`
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+/// The `[sandbox]` section, which restricts what the remapper can do
+/// once it has opened the devices
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SandboxConfig {
+    /// Use Landlock to limit the files that can be opened
+    pub landlock: bool,
+    /// Use a seccomp filter to limit the system calls that can be made
+    pub seccomp: bool,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        Self {
+            landlock: true,
+            seccomp: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheduler {
+    /// The normal time-sharing scheduler
+    #[default]
+    Other,
+    /// SCHED_FIFO: runs ahead of every normal thread until it blocks
+    Fifo,
+    /// SCHED_RR: like `fifo`, but takes turns with threads of the
+    /// same priority
+    Rr,
 }
 
 impl MappingConfig {
+    /// Load the configuration from `path`, along with the files that
+    /// it includes and any `*.toml` fragments in the directory of the
+    /// same name with a `.d` extension; for example `/etc/evremap.d`
+    /// for `/etc/evremap.toml`.
+    ///
+    /// Included files are merged ahead of the file that includes them,
+    /// and the fragments are merged in filename order after the main
+    /// file.  Later `device_name` and `phys` settings override earlier
+    /// ones.
     pub fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
         let path = path.as_ref();
-        let toml_data = std::fs::read_to_string(path)
-            .context(format!("reading toml from {}", path.display()))?;
-        let config_file: ConfigFile =
-            toml::from_str(&toml_data).context(format!("parsing toml from {}", path.display()))?;
+        let mut loader = ConfigLoader::default();
+        loader.load(path)?;
+
+        let fragment_dir = path.with_extension("d");
+        if fragment_dir.is_dir() {
+            let mut fragments = vec![];
+            for entry in std::fs::read_dir(&fragment_dir)
+                .context(format!("reading {}", fragment_dir.display()))?
+            {
+                let fragment = entry?.path();
+                if fragment
+                    .extension()
+                    .map(|ext| ext == "toml")
+                    .unwrap_or(false)
+                {
+                    fragments.push(fragment);
+                }
+            }
+            fragments.sort();
+            for fragment in fragments {
+                loader.load(&fragment)?;
+            }
+        }
+
+        let device_name = match loader.device_name {
+            Some(name) => name,
+            None => anyhow::bail!(
+                "No device_name was specified in {} or the files that it includes",
+                path.display()
+            ),
+        };
         let mut mappings = vec![];
-        for dual in config_file.dual_role {
+        let mut origins = vec![];
+        for (dual, origin) in loader.dual_role {
             mappings.push(dual.into());
+            origins.push(origin);
         }
-        for remap in config_file.remap {
+        for (remap, origin) in loader.remap {
             mappings.push(remap.into());
+            origins.push(origin);
         }
         Ok(Self {
-            device_name: config_file.device_name,
-            phys: config_file.phys,
+            device_name,
+            phys: loader.phys,
+            group_siblings: loader.group_siblings,
             mappings,
+            modifiers: loader.modifiers,
+            origins,
+            files: loader.files,
+            fragment_dir,
+            definitions: loader.definitions,
+            watchdog: loader.watchdog,
+            kill_switch: loader
+                .kill_switch
+                .unwrap_or_else(|| Some(KillSwitch::fallback())),
+            runtime: loader.runtime,
+            privileges: loader.privileges,
+            sandbox: loader.sandbox,
         })
     }
 }
 
+#[cfg(test)]
+impl MappingConfig {
+    /// Load a configuration file with the contents `toml`, which need
+    /// not set `device_name`
+    pub fn from_toml(name: &str, toml: &str) -> Self {
+        let path =
+            std::env::temp_dir().join(format!("evremap-{}-{}.toml", std::process::id(), name));
+        std::fs::write(&path, format!("device_name = \"test\"\n{}", toml)).unwrap();
+        let config = Self::from_file(&path);
+        std::fs::remove_file(&path).unwrap();
+        config.unwrap()
+    }
+}
+
+/// Identifies where a rule was defined
+#[derive(Debug, Clone)]
+pub struct RuleOrigin {
+    pub file: PathBuf,
+    /// The table array that holds the rule; `dual_role`, `remap`
+    /// or `map_range`
+    pub section: &'static str,
+    /// The 1-based position of the rule within its section of the file
+    pub index: usize,
+}
+
+impl std::fmt::Display for RuleOrigin {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} #{}",
+            self.file.display(),
+            self.section,
+            self.index
+        )
+    }
+}
+
+/// Accumulates the contents of a set of configuration files,
+/// remembering which file each rule came from
+#[derive(Default)]
+struct ConfigLoader {
+    device_name: Option<String>,
+    phys: Option<String>,
+    group_siblings: bool,
+    watchdog: WatchdogConfig,
+    /// The kill switch of the last `[kill_switch]` section, which is
+    /// None if that disabled it
+    kill_switch: Option<Option<KillSwitch>>,
+    runtime: RuntimeConfig,
+    privileges: Option<PrivilegesConfig>,
+    sandbox: Option<SandboxConfig>,
+    dual_role: Vec<(DualRoleConfig, RuleOrigin)>,
+    remap: Vec<(RemapConfig, RuleOrigin)>,
+    modifiers: HashSet<KeyCode>,
+    definitions: Definitions,
+    /// The file that defined each of the virtual modifiers
+    virtual_origins: HashMap<String, PathBuf>,
+    /// The file that defined each of the groups
+    group_origins: HashMap<String, PathBuf>,
+    files: Vec<PathBuf>,
+    /// The canonical paths of `files`, so that a file which is
+    /// included more than once is only loaded the first time
+    loaded: HashSet<PathBuf>,
+    /// The canonical paths of the files that are currently being
+    /// loaded, used to detect include cycles
+    loading: Vec<PathBuf>,
+}
+
+impl ConfigLoader {
+    fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        let canonical = path
+            .canonicalize()
+            .context(format!("reading toml from {}", path.display()))?;
+        if self.loading.contains(&canonical) {
+            anyhow::bail!("{} includes itself", path.display());
+        }
+        if self.loaded.contains(&canonical) {
+            log::debug!("{} was already included", path.display());
+            return Ok(());
+        }
+
+        let toml_data = std::fs::read_to_string(path)
+            .context(format!("reading toml from {}", path.display()))?;
+        let config_file: ConfigFile =
+            toml::from_str(&toml_data).context(format!("parsing toml from {}", path.display()))?;
+
+        self.loading.push(canonical.clone());
+        let base = path.parent().unwrap_or(Path::new(""));
+        for pattern in &config_file.include {
+            for include in expand_include(base, pattern).context(format!(
+                "expanding include `{}` in {}",
+                pattern,
+                path.display()
+            ))? {
+                self.load(&include)
+                    .context(format!("included from {}", path.display()))?;
+            }
+        }
+        self.loading.pop();
+        self.files.push(path.to_path_buf());
+        self.loaded.insert(canonical);
+
+        if let Some(name) = config_file.device_name {
+            if let Some(prior) = self.device_name.replace(name) {
+                log::debug!("{} overrides device_name `{}`", path.display(), prior);
+            }
+        }
+        if let Some(phys) = config_file.phys {
+            self.phys.replace(phys);
+        }
+        if let Some(group_siblings) = config_file.group_siblings {
+            self.group_siblings = group_siblings;
+        }
+        if let Some(watchdog) = config_file.watchdog {
+            self.watchdog = watchdog;
+        }
+        if let Some(runtime) = config_file.runtime {
+            if let Some(priority) = runtime.priority.filter(|p| !(1..=99).contains(p)) {
+                anyhow::bail!(
+                    "{}: the [runtime] priority must be between 1 and 99, not {}",
+                    path.display(),
+                    priority
+                );
+            }
+            self.runtime = runtime;
+        }
+        if let Some(privileges) = config_file.privileges {
+            self.privileges.replace(privileges);
+        }
+        if let Some(sandbox) = config_file.sandbox {
+            self.sandbox.replace(sandbox);
+        }
+        self.define_virtual_modifiers(path, &toml_data, &config_file.virtual_modifiers)?;
+        self.define_groups(path, &toml_data, &config_file.groups)?;
+
+        let resolver = Resolver {
+            path,
+            source: &toml_data,
+            defs: &self.definitions,
+        };
+
+        if let Some(entry) = &config_file.kill_switch {
+            self.kill_switch.replace(entry.resolve(&resolver)?);
+        }
+
+        for name in &config_file.modifier_keys {
+            for key in resolver.keys(std::slice::from_ref(name))? {
+                if !matches!(key.code, EventCode::EV_KEY(_)) {
+                    return Err(resolver.error_at(
+                        name,
+                        format!(
+                            "{} cannot be a modifier; only keys can",
+                            resolver.defs.code_name(&key.code)
+                        ),
+                    ));
+                }
+                self.modifiers.insert(key.code);
+            }
+        }
+
+        for (idx, dual) in config_file.dual_role.iter().enumerate() {
+            let dual = dual.resolve(&resolver)?;
+            if let Some((prior, prior_origin)) = self.dual_role.iter().find(|(prior, _)| {
+                prior.input.iter().collect::<HashSet<_>>() == dual.input.iter().collect()
+                    && prior.unless == dual.unless
+            }) {
+                if prior_origin.file != path && (prior.hold != dual.hold || prior.tap != dual.tap) {
+                    anyhow::bail!(
+                        "dual_role for {} in {} conflicts with the one in {}",
+                        prior
+                            .input
+                            .iter()
+                            .map(|k| self.definitions.code_name(&k.code))
+                            .collect::<Vec<_>>()
+                            .join("+"),
+                        path.display(),
+                        prior_origin.file.display()
+                    );
+                }
+            }
+            let origin = RuleOrigin {
+                file: path.to_path_buf(),
+                section: "dual_role",
+                index: idx + 1,
+            };
+            self.dual_role.push((dual, origin));
+        }
+
+        // Remap and map_range rules apply in the order that they
+        // appear in the file
+        let mut remaps = vec![];
+        for (idx, remap) in config_file.remap.iter().enumerate() {
+            let origin = RuleOrigin {
+                file: path.to_path_buf(),
+                section: "remap",
+                index: idx + 1,
+            };
+            remaps.push((
+                remap.span().start,
+                remap.get_ref().resolve(&resolver)?,
+                origin,
+            ));
+        }
+        for (idx, range) in config_file.map_range.iter().enumerate() {
+            let origin = RuleOrigin {
+                file: path.to_path_buf(),
+                section: "map_range",
+                index: idx + 1,
+            };
+            remaps.push((
+                range.span().start,
+                range.get_ref().resolve(&resolver)?,
+                origin,
+            ));
+        }
+        remaps.sort_by_key(|(position, _, _)| *position);
+        for (_, rules, origin) in remaps {
+            for remap in rules {
+                self.add_remap(path, remap, origin.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_remap(
+        &mut self,
+        path: &Path,
+        remap: RemapConfig,
+        origin: RuleOrigin,
+    ) -> anyhow::Result<()> {
+        let input: HashSet<&KeyCodeWrapper> = remap.input.iter().collect();
+        if let Some((prior, prior_origin)) = self.remap.iter().find(|(prior, _)| {
+            prior.input.iter().collect::<HashSet<_>>() == input
+                && prior.modifiers == remap.modifiers
+                && prior.unless.iter().collect::<HashSet<_>>()
+                    == remap.unless.iter().collect::<HashSet<_>>()
+                && prior.exact == remap.exact
+        }) {
+            let output: HashSet<&KeyCodeWrapper> = remap.output.iter().collect();
+            let differs = prior.output.iter().collect::<HashSet<_>>() != output
+                || prior.mask != remap.mask
+                || prior.ordered != remap.ordered
+                || prior.suppress != remap.suppress
+                || (remap.ordered && prior.output != remap.output);
+            if prior_origin.file != path && differs {
+                anyhow::bail!(
+                    "remap for [{}] in {} conflicts with the one in {}",
+                    remap
+                        .input
+                        .iter()
+                        .map(|k| self.definitions.code_name(&k.code))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    path.display(),
+                    prior_origin.file.display()
+                );
+            }
+        }
+        self.remap.push((remap, origin));
+        Ok(())
+    }
+
+    /// Add the virtual modifiers defined in `source`, the contents of
+    /// `path`.  A modifier may be made up of others from the same file,
+    /// so each pass defines those whose keys are all known, until none
+    /// are left.
+    fn define_virtual_modifiers(
+        &mut self,
+        path: &Path,
+        source: &str,
+        table: &BTreeMap<Spanned<String>, Vec<KeyName>>,
+    ) -> anyhow::Result<()> {
+        let mut pending: Vec<(&Spanned<String>, &Vec<KeyName>)> = table.iter().collect();
+        while !pending.is_empty() {
+            let mut first_err = None;
+            let mut defined = vec![];
+            for (name, members) in &pending {
+                let resolver = Resolver {
+                    path,
+                    source,
+                    defs: &self.definitions,
+                };
+                match resolver.keys(members) {
+                    Err(err) => {
+                        first_err.get_or_insert(err);
+                    }
+                    keys => defined.push((*name, resolver.virtual_modifier(name, keys?)?)),
+                }
+            }
+            if defined.is_empty() {
+                if let Some(err) = first_err {
+                    return Err(err);
+                }
+            }
+            for (name, keys) in defined {
+                let upper = name.get_ref().to_uppercase();
+                if let Some(prior) = self.virtual_origins.get(&upper) {
+                    if prior != path
+                        && self.definitions.virtual_modifiers.get(&upper) != Some(&keys)
+                    {
+                        anyhow::bail!(
+                            "virtual modifier {} in {} conflicts with the one in {}",
+                            upper,
+                            path.display(),
+                            prior.display()
+                        );
+                    }
+                }
+                self.virtual_origins
+                    .insert(upper.clone(), path.to_path_buf());
+                self.definitions.define_virtual_modifier(upper, keys);
+                pending.retain(|(pending_name, _)| *pending_name != name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the groups defined in `source`, the contents of `path`.  As
+    /// with virtual modifiers, a group may refer to others from the
+    /// same file.
+    fn define_groups(
+        &mut self,
+        path: &Path,
+        source: &str,
+        table: &BTreeMap<Spanned<String>, KeyName>,
+    ) -> anyhow::Result<()> {
+        let mut pending: Vec<(&Spanned<String>, &KeyName)> = table.iter().collect();
+        while !pending.is_empty() {
+            let mut first_err = None;
+            let mut defined = vec![];
+            for (name, members) in &pending {
+                let resolver = Resolver {
+                    path,
+                    source,
+                    defs: &self.definitions,
+                };
+                let valid_name = name
+                    .get_ref()
+                    .starts_with(|c: char| c.is_ascii_alphabetic())
+                    && name
+                        .get_ref()
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'_');
+                if !valid_name {
+                    return Err(resolver.error_at(
+                        name,
+                        format!(
+                            "`{}` is not a valid name for a group; \
+                             use letters, digits and underscores",
+                            name.get_ref()
+                        ),
+                    ));
+                }
+                match resolver.group(members) {
+                    Err(err) => {
+                        first_err.get_or_insert(err);
+                    }
+                    keys => defined.push((*name, keys?)),
+                }
+            }
+            if defined.is_empty() {
+                if let Some(err) = first_err {
+                    return Err(err);
+                }
+            }
+            for (name, keys) in defined {
+                let name_str = name.get_ref();
+                if let Some(prior) = self.group_origins.get(name_str) {
+                    if prior != path && self.definitions.groups.get(name_str) != Some(&keys) {
+                        anyhow::bail!(
+                            "group @{} in {} conflicts with the one in {}",
+                            name_str,
+                            path.display(),
+                            prior.display()
+                        );
+                    }
+                }
+                self.group_origins
+                    .insert(name_str.clone(), path.to_path_buf());
+                self.definitions.groups.insert(name_str.clone(), keys);
+                pending.retain(|(pending_name, _)| *pending_name != name);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Resolve an `include` pattern relative to the directory of the
+/// file that contains it.  A pattern without wildcards must name
+/// an existing file, whereas a wildcard may match nothing at all.
+fn expand_include(base: &Path, pattern: &str) -> anyhow::Result<Vec<PathBuf>> {
+    let full = base.join(pattern);
+    let full = full.to_str().context("include path is not valid UTF-8")?;
+    let mut paths = vec![];
+    for entry in glob::glob(full)? {
+        paths.push(entry?);
+    }
+    if paths.is_empty() && !pattern.contains(['*', '?', '[']) {
+        anyhow::bail!("{} does not exist", full);
+    }
+    paths.sort();
+    Ok(paths)
+}
+
 #[derive(Debug, Clone)]
 pub enum Mapping {
     DualRole {
-        input: KeyCode,
+        /// A single key, or a chord of keys that must all be held
+        input: Vec<KeyCode>,
         hold: Vec<KeyCode>,
         tap: Vec<KeyCode>,
+        /// Press `hold` in the order given, rather than modifiers first
+        ordered: bool,
+        /// The rule does not apply if one of these keys was held when
+        /// `input` was pressed
+        unless: Vec<KeyCode>,
     },
     Remap {
         input: HashSet<KeyCodeWrapper>,
-        output: HashSet<KeyCodeWrapper>,
+        output: Vec<KeyCodeWrapper>,
+        /// Press `output` in the order given, rather than modifiers first
+        ordered: bool,
+        modifiers: ModifierPolicy,
+        /// Held keys that are released while the rule applies
+        mask: Vec<KeyCode>,
+        /// The rule does not match while one of these keys is held
+        unless: Vec<KeyCode>,
+        /// The rule only matches when no keys other than `input` are held
+        exact: bool,
+        /// The keys that the rule consumed stay silent until they are
+        /// released, even if the rule stops applying before then
+        suppress: bool,
     },
 }
 
+/// What a remap rule does with held modifiers that are not part of
+/// its input
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModifierPolicy {
+    /// The other modifiers remain held along with the output
+    #[default]
+    Passthrough,
+    /// The other modifiers are released while the rule applies
+    Consume,
+    /// The rule only matches when no other modifiers are held
+    Exact,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 #[serde(try_from = "String")]
 pub struct KeyCodeWrapper {
@@ -89,92 +716,1401 @@ impl From<KeyCodeWrapper> for KeyCode {
     }
 }
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, PartialEq)]
 pub enum ConfigError {
-    #[error("Invalid key `{0}`.  Use `evremap list-keys` to see possible keys.")]
-    InvalidKey(String),
-    #[error("Impossible: parsed KEY_XXX but not into an EV_KEY")]
-    ImpossibleParseKey,
+    #[error("Invalid key `{name}`.  {}", did_you_mean(.name))]
+    InvalidKey { name: String },
+    #[error(
+        "Invalid scale `{scale}` for `{name}`.  The scale must be a whole number, eg: `{name}+2`"
+    )]
+    InvalidScale { name: String, scale: String },
+    #[error("`{expr}` is missing a key name")]
+    MissingKey { expr: String },
+    #[error("`{expr}` names {count} keys, but only a single key is allowed here")]
+    NotSingleKey { expr: String, count: usize },
+    #[error("`{name}` is out of range; the highest code of its type is {max:#x}")]
+    CodeOutOfRange { name: String, max: u32 },
+    #[error("`{name}` can only be used as an input")]
+    InputOnly { name: String },
+    #[error("`{name}` is not a physical key.  Use a key such as KEY_LEFTCTRL instead.")]
+    NotPhysical { name: String },
+    #[error("Unknown group `@{name}`.  Groups are defined in the `[groups]` table.")]
+    UnknownGroup { name: String },
+    #[error("Invalid range `{expr}`: {reason}")]
+    InvalidRange { expr: String, reason: String },
+    #[error("`{expr}` is a group of keys, which can only be used in the input of a remap rule or in a map_range rule")]
+    GroupNotAllowed { expr: String },
+}
+
+/// The suggestion is only worked out when the error is displayed, as
+/// many of these errors are discarded while trying other ways of
+/// parsing a key expression
+fn did_you_mean(name: &str) -> String {
+    match suggest_key(name) {
+        Some(name) => format!("Did you mean {}?", name),
+        None => "Use `evremap list-keys` to see possible keys.".to_string(),
+    }
+}
+
+impl ConfigError {
+    pub fn invalid_key(name: &str) -> Self {
+        ConfigError::InvalidKey {
+            name: name.to_string(),
+        }
+    }
+}
+
+/// The names of the keys and axes that can be used in a configuration
+/// file, as printed by `evremap list-keys`
+pub fn known_key_names() -> Vec<String> {
+    let mut keys: Vec<String> = EventCode::EV_KEY(EV_KEY::KEY_RESERVED)
+        .iter()
+        .chain(EventCode::EV_REL(EV_REL::REL_X).iter())
+        .chain(EventCode::EV_ABS(EV_ABS::ABS_X).iter())
+        .filter_map(|code| match code {
+            EventCode::EV_KEY(_) | EventCode::EV_REL(_) | EventCode::EV_ABS(_) => {
+                Some(format!("{}", code))
+            }
+            _ => None,
+        })
+        .collect();
+    keys.sort();
+    keys
+}
+
+/// Find the known key name that is most likely to have been meant by `name`
+fn suggest_key(name: &str) -> Option<String> {
+    let upper = name.to_uppercase();
+    if matches!(upper.as_str(), "KEY" | "BTN" | "REL" | "ABS") {
+        // Nothing to go on but the type
+        return None;
+    }
+    let names = known_key_names();
+    // Catch the wrong case and a missing prefix, eg: `leftctrl`
+    if let Some(exact) = names.iter().find(|known| {
+        **known == upper || known.split_once('_').map(|(_, rest)| rest) == Some(&upper)
+    }) {
+        return Some(exact.clone());
+    }
+    // Without a prefix, compare against the part of the names after it
+    let has_prefix = upper.contains('_');
+    names
+        .into_iter()
+        .map(|known| {
+            let candidate = match known.split_once('_') {
+                Some((_, rest)) if !has_prefix => rest,
+                _ => &known,
+            };
+            (strsim::jaro_winkler(&upper, candidate), known)
+        })
+        .filter(|(confidence, _)| *confidence > 0.8)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, known)| known)
 }
 
 impl std::convert::TryFrom<String> for KeyCodeWrapper {
     type Error = ConfigError;
+    /// Parse a key expression that names exactly one key
     fn try_from(s: String) -> Result<KeyCodeWrapper, Self::Error> {
+        let mut keys = KeyExpr::parse(&s)?.0;
+        if keys.len() != 1 {
+            return Err(ConfigError::NotSingleKey {
+                expr: s,
+                count: keys.len(),
+            });
+        }
+        Ok(keys.remove(0))
+    }
+}
+
+impl KeyCodeWrapper {
+    /// Parse a `KEY_XXX`, `BTN_XXX`, `REL_XXX` or `ABS_XXX` name, which
+    /// may be followed by a `+` or `-` direction and scale
+    pub fn from_name(s: &str) -> Result<KeyCodeWrapper, ConfigError> {
         let mut scale: i32 = 1;
         let name: &str;
         match s.rmatch_indices(&['+', '-']).next() {
             None => {
-                name = &s;
+                name = s;
                 scale = 0;
             },
             Some(m) => {
                 let _scale;
                 (name, _scale) = s.split_at(m.0);
                 if _scale.len() > 1 {
-                    scale = _scale.parse::<i32>().unwrap();
+                    scale = _scale
+                        .parse::<i32>()
+                        .map_err(|_| ConfigError::InvalidScale {
+                            name: name.to_string(),
+                            scale: _scale.to_string(),
+                        })?;
                 } else if _scale == "-" {
                     scale = -1;
                 }
             },
         };
-        let mut prefix = name.split_once("_").unwrap().0;
+        if name.contains('\0') {
+            return Err(ConfigError::invalid_key(name));
+        }
+        let (mut prefix, number) = match name.split_once('_') {
+            Some((prefix, rest)) => (prefix, parse_code_number(rest)),
+            None => return Err(ConfigError::invalid_key(name)),
+        };
+        if prefix == "SCAN" {
+            return match number {
+                Some(scan) => Ok(KeyCodeWrapper {
+                    code: scan_code(scan),
+                    scale: 1,
+                }),
+                None => Err(ConfigError::invalid_key(name)),
+            };
+        }
         if prefix == "BTN" {
             prefix = "KEY";
         }
         if prefix == "KEY" && scale == 0 {
             scale = 1;
         }
-        match EventType::from_str(&*("EV_".to_string() + prefix)) {
-            Some(event_type) => match EventCode::from_str(&event_type, &name) {
-                Some(code) => Ok(KeyCodeWrapper { code, scale }),
-                None => Err(ConfigError::InvalidKey(name.to_string())),
+        match EventType::from_str(&("EV_".to_string() + prefix)) {
+            Some(event_type) => match (EventCode::from_str(&event_type, name), number) {
+                (Some(code), _) => Ok(KeyCodeWrapper { code, scale }),
+                (None, Some(number)) => {
+                    let max = match event_type {
+                        EventType::EV_KEY => EV_KEY::KEY_MAX as u32,
+                        EventType::EV_REL => EV_REL::REL_MAX as u32,
+                        EventType::EV_ABS => EV_ABS::ABS_MAX as u32,
+                        _ => return Err(ConfigError::invalid_key(name)),
+                    };
+                    if number > max {
+                        return Err(ConfigError::CodeOutOfRange {
+                            name: name.to_string(),
+                            max,
+                        });
+                    }
+                    let code = evdev_rs::util::int_to_event_code(event_type as u32, number);
+                    Ok(KeyCodeWrapper { code, scale })
+                }
+                (None, None) => Err(ConfigError::invalid_key(name)),
             },
-            None => Err(ConfigError::InvalidKey(name.to_string())),
+            None => Err(ConfigError::invalid_key(name)),
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// Parse the `0x1d7` part of a numeric name such as `KEY_0x1d7`.
+/// Only hex is accepted, as `KEY_1` is already the name of a key.
+pub fn parse_code_number(s: &str) -> Option<u32> {
+    let hex = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X"))?;
+    u32::from_str_radix(hex, 16).ok()
+}
+
+/// Returns the pseudo code for the `MSC_SCAN` scancode `scan`.  It is
+/// written as `SCAN_0x70039` in the configuration file, and stands in
+/// for whichever key event follows that scancode, so that keys which
+/// the kernel maps to `KEY_UNKNOWN` or to nothing useful can be remapped
+pub fn scan_code(scan: u32) -> KeyCode {
+    EventCode::EV_UNK {
+        event_type: EventType::EV_MSC as u32,
+        event_code: scan,
+    }
+}
+
+/// Returns the scancode of a pseudo code made by `scan_code`
+pub fn as_scan_code(code: &KeyCode) -> Option<u32> {
+    match code {
+        EventCode::EV_UNK {
+            event_type,
+            event_code,
+        } if *event_type == EventType::EV_MSC as u32 => Some(*event_code),
+        _ => None,
+    }
+}
+
+/// Returns true if `code` is pressed and released like a key, rather
+/// than carrying a relative or absolute value
+pub fn is_key_code(code: &KeyCode) -> bool {
+    match code {
+        EventCode::EV_KEY(_) => true,
+        EventCode::EV_UNK { event_type, .. } => {
+            *event_type == EventType::EV_KEY as u32
+                || *event_type == EventType::EV_MSC as u32
+                || *event_type == EV_VIRTUAL
+        }
+        _ => false,
+    }
+}
+
+/// The event type of the codes of virtual modifiers.  The kernel doesn't
+/// use it, so they cannot be confused with the codes of real events.
+const EV_VIRTUAL: u32 = 0x1e;
+
+/// The side-agnostic modifiers, each of which is held while either
+/// of its keys is held
+const LOGICAL_MODIFIERS: &[(&str, [EV_KEY; 2])] = &[
+    ("CTRL", [EV_KEY::KEY_LEFTCTRL, EV_KEY::KEY_RIGHTCTRL]),
+    ("SHIFT", [EV_KEY::KEY_LEFTSHIFT, EV_KEY::KEY_RIGHTSHIFT]),
+    ("ALT", [EV_KEY::KEY_LEFTALT, EV_KEY::KEY_RIGHTALT]),
+    ("META", [EV_KEY::KEY_LEFTMETA, EV_KEY::KEY_RIGHTMETA]),
+];
+
+/// Returns the virtual modifier code with index `idx`.  The logical
+/// modifiers have the first indices, and the `Definitions` of each
+/// configuration allocate those that follow for its internal modifiers.
+pub fn virtual_code(idx: usize) -> KeyCode {
+    EventCode::EV_UNK {
+        event_type: EV_VIRTUAL,
+        event_code: idx as u32,
+    }
+}
+
+/// Returns the index of the virtual modifier `code`
+pub fn virtual_index(code: &KeyCode) -> Option<usize> {
+    match code {
+        EventCode::EV_UNK {
+            event_type,
+            event_code,
+        } if *event_type == EV_VIRTUAL => Some(*event_code as usize),
+        _ => None,
+    }
+}
+
+/// Returns true if `code` is a virtual modifier.  These take part in
+/// matching rules but are never emitted.
+pub fn is_virtual_code(code: &KeyCode) -> bool {
+    virtual_index(code).is_some()
+}
+
+/// The names of the side-agnostic modifiers, indexed by their code
+pub fn logical_modifier_names() -> impl Iterator<Item = &'static str> {
+    LOGICAL_MODIFIERS.iter().map(|(name, _)| *name)
+}
+
+/// Returns the code of the side-agnostic modifier `name`
+pub fn logical_modifier_code(name: &str) -> Option<KeyCode> {
+    LOGICAL_MODIFIERS
+        .iter()
+        .position(|(logical, _)| *logical == name)
+        .map(virtual_code)
+}
+
+pub fn is_logical_modifier_name(name: &str) -> bool {
+    LOGICAL_MODIFIERS
+        .iter()
+        .any(|(logical, _)| *logical == name)
+}
+
+/// Returns the keys of a side-agnostic modifier, left hand first
+pub fn logical_modifier_keys(code: &KeyCode) -> Option<[KeyCode; 2]> {
+    match code {
+        EventCode::EV_UNK {
+            event_type,
+            event_code,
+        } if *event_type == EV_VIRTUAL => LOGICAL_MODIFIERS
+            .get(*event_code as usize)
+            .map(|(_, [left, right])| [EventCode::EV_KEY(*left), EventCode::EV_KEY(*right)]),
+        _ => None,
+    }
+}
+
+/// The codes of the side-agnostic modifiers
+pub fn logical_modifiers() -> impl Iterator<Item = KeyCode> {
+    (0..LOGICAL_MODIFIERS.len()).map(virtual_code)
+}
+
+/// The name of `code`, as it would be written in a configuration file.
+/// Codes that libevdev has no name for are shown in their numeric form,
+/// as are internal modifiers; `Definitions::code_name` knows their names.
+pub fn code_name(code: &KeyCode) -> String {
+    if let Some(scan) = as_scan_code(code) {
+        return format!("SCAN_{:#x}", scan);
+    }
+    if let Some(idx) = virtual_index(code) {
+        return match LOGICAL_MODIFIERS.get(idx) {
+            Some((name, _)) => name.to_string(),
+            None => format!("VIRTUAL_{}", idx),
+        };
+    }
+    if let EventCode::EV_UNK {
+        event_type,
+        event_code,
+    } = code
+    {
+        let prefix = match evdev_rs::enums::int_to_event_type(*event_type) {
+            Some(EventType::EV_KEY) => "KEY",
+            Some(EventType::EV_REL) => "REL",
+            Some(EventType::EV_ABS) => "ABS",
+            _ => return format!("{:?}", code),
+        };
+        return format!("{}_{:#x}", prefix, event_code);
+    }
+    let name = code.to_string();
+    if name.is_empty() {
+        format!("{:?}", code)
+    } else {
+        name
+    }
+}
+
+impl std::fmt::Display for KeyCodeWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.code, self.scale) {
+            (code, 1) if is_key_code(&code) => write!(f, "{}", code_name(&code)),
+            (_, 0) => write!(f, "{}", code_name(&self.code)),
+            (_, 1) => write!(f, "{}+", code_name(&self.code)),
+            (_, -1) => write!(f, "{}-", code_name(&self.code)),
+            (_, scale) => write!(f, "{}{:+}", code_name(&self.code), scale),
+        }
+    }
+}
+
+/// A key name as written in a configuration file, along with its
+/// location so that problems with it can be reported precisely
+type KeyName = Spanned<String>;
+
+/// Resolves the key expressions in `source`, the contents of `path`,
+/// using the names that have been defined so far
+struct Resolver<'a> {
+    path: &'a Path,
+    source: &'a str,
+    defs: &'a Definitions,
+}
+
+impl<'a> Resolver<'a> {
+    /// Parse the expression `name`, which must name a physical key or
+    /// a chord of them
+    fn chord(&self, name: &KeyName) -> anyhow::Result<Vec<KeyCodeWrapper>> {
+        let mut keys = vec![];
+        for key in self.expr(name)? {
+            if is_virtual_code(&key.code) {
+                let err = ConfigError::NotPhysical {
+                    name: self.defs.code_name(&key.code),
+                };
+                return Err(self.error_at(name, err));
+            }
+            if !keys.contains(&key) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    fn expr(&self, name: &KeyName) -> anyhow::Result<Vec<KeyCodeWrapper>> {
+        if is_group_expr(name.get_ref()) {
+            let err = ConfigError::GroupNotAllowed {
+                expr: name.get_ref().clone(),
+            };
+            return Err(self.error_at(name, err));
+        }
+        KeyExpr::parse_with(name.get_ref(), self.defs)
+            .map(|expr| expr.0)
+            .map_err(|err| self.error_at(name, err))
+    }
+
+    /// Parse each of the key expressions in `names`.  An expression
+    /// such as `alt+left` contributes more than one key.
+    fn keys(&self, names: &[KeyName]) -> anyhow::Result<Vec<KeyCodeWrapper>> {
+        let mut keys = vec![];
+        for name in names {
+            keys.extend(self.expr(name)?);
+        }
+        Ok(keys)
+    }
+
+    /// Parse the inputs of a remap rule.  A group in `names` stands for
+    /// each of its keys in turn, so this returns every combination.
+    fn input_combinations(&self, names: &[KeyName]) -> anyhow::Result<Vec<Vec<KeyCodeWrapper>>> {
+        let mut combinations = vec![vec![]];
+        for name in names {
+            if is_group_expr(name.get_ref()) {
+                let members = self.group(name)?;
+                combinations = combinations
+                    .iter()
+                    .flat_map(|keys| {
+                        members.iter().map(move |member| {
+                            let mut keys = keys.clone();
+                            keys.push(member.clone());
+                            keys
+                        })
+                    })
+                    .collect();
+            } else {
+                let keys = self.expr(name)?;
+                for combination in &mut combinations {
+                    combination.extend(keys.iter().cloned());
+                }
+            }
+        }
+        Ok(combinations)
+    }
+
+    /// Parse the group of keys `name`
+    fn group(&self, name: &KeyName) -> anyhow::Result<Vec<KeyCodeWrapper>> {
+        parse_group(name.get_ref(), self.defs).map_err(|err| self.error_at(name, err))
+    }
+
+    /// Parse the key expressions in `names` for use as the output of
+    /// a rule
+    fn output_keys(&self, names: &[KeyName]) -> anyhow::Result<Vec<KeyCodeWrapper>> {
+        let mut keys = vec![];
+        for name in names {
+            for key in self.expr(name)? {
+                keys.push(self.output_key(name, key)?);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Parse the keys in `names` for use as the `mask` of a rule
+    fn mask_keys(&self, names: &[KeyName]) -> anyhow::Result<Vec<KeyCodeWrapper>> {
+        let mut keys = vec![];
+        for name in names {
+            for key in self.expr(name)? {
+                if !is_key_code(&key.code) || as_scan_code(&key.code).is_some() {
+                    return Err(
+                        self.error_at(name, format!("{} cannot be masked; only keys can", key))
+                    );
+                }
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Parse the keys in `names` for use as the `unless` of a rule
+    fn unless_keys(&self, names: &[KeyName]) -> anyhow::Result<Vec<KeyCodeWrapper>> {
+        let mut keys = vec![];
+        for name in names {
+            for key in self.expr(name)? {
+                if !is_key_code(&key.code) {
+                    return Err(self.error_at(
+                        name,
+                        format!("{} cannot be used in `unless`; only keys can", key),
+                    ));
+                }
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Check that `key`, from `name`, can be emitted.  Side-agnostic
+    /// modifiers produce their left hand key.
+    fn output_key(&self, name: &KeyName, key: KeyCodeWrapper) -> anyhow::Result<KeyCodeWrapper> {
+        if as_scan_code(&key.code).is_some() {
+            let err = ConfigError::InputOnly {
+                name: key.to_string(),
+            };
+            return Err(self.error_at(name, err));
+        }
+        Ok(match logical_modifier_keys(&key.code) {
+            Some([left, _]) => KeyCodeWrapper {
+                code: left,
+                scale: 1,
+            },
+            None => key,
+        })
+    }
+
+    fn error_at(&self, name: &KeyName, err: impl std::fmt::Display) -> anyhow::Error {
+        error_at(self.path, self.source, name.span(), err)
+    }
+
+    /// Validate the definition of the virtual modifier `name`,
+    /// which is made up of `keys`
+    fn virtual_modifier(
+        &self,
+        name: &Spanned<String>,
+        keys: Vec<KeyCodeWrapper>,
+    ) -> anyhow::Result<Vec<KeyCodeWrapper>> {
+        let upper = name.get_ref().to_uppercase();
+        let valid_name = upper.starts_with(|c: char| c.is_ascii_alphabetic())
+            && upper
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'_');
+        if !valid_name {
+            return Err(self.error_at(
+                name,
+                format!(
+                    "`{}` is not a valid name for a virtual modifier; \
+                     use letters, digits and underscores",
+                    name.get_ref()
+                ),
+            ));
+        }
+        if is_logical_modifier_name(&upper) {
+            return Err(self.error_at(
+                name,
+                format!(
+                    "`{}` is a built-in modifier and cannot be redefined",
+                    name.get_ref()
+                ),
+            ));
+        }
+        let is_key = KeyExpr::parse_with(&upper, &Definitions::empty()).is_ok();
+        if is_key && !self.defs.virtual_modifiers.contains_key(&upper) {
+            return Err(self.error_at(
+                name,
+                format!("`{}` is already the name of a key", name.get_ref()),
+            ));
+        }
+        if let Some(key) = keys
+            .iter()
+            .find(|k| !is_key_code(&k.code) || as_scan_code(&k.code).is_some())
+        {
+            return Err(self.error_at(
+                name,
+                format!(
+                    "{} cannot be part of a virtual modifier; only keys can",
+                    key
+                ),
+            ));
+        }
+        Ok(keys)
+    }
+}
+
+/// Describe `err` as occurring at `span` within `source`, the contents
+/// of `path`, quoting the offending line in the same style as the errors
+/// reported by the toml parser
+fn error_at(
+    path: &Path,
+    source: &str,
+    span: std::ops::Range<usize>,
+    err: impl std::fmt::Display,
+) -> anyhow::Error {
+    let line_start = source[..span.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = source[span.start..]
+        .find('\n')
+        .map(|i| span.start + i)
+        .unwrap_or(source.len());
+    let line = source[..span.start].matches('\n').count() + 1;
+    let column = source[line_start..span.start].chars().count() + 1;
+    let width = source[span.start..span.end.min(line_end)]
+        .chars()
+        .count()
+        .max(1);
+    let gutter = " ".repeat(line.to_string().len());
+    anyhow::anyhow!(
+        "{}:{}:{}: {}\n{} |\n{} | {}\n{} | {}{}",
+        path.display(),
+        line,
+        column,
+        err,
+        gutter,
+        line,
+        &source[line_start..line_end],
+        gutter,
+        " ".repeat(column - 1),
+        "^".repeat(width)
+    )
+}
+
+#[derive(Debug)]
 struct DualRoleConfig {
-    input: KeyCodeWrapper,
+    input: Vec<KeyCodeWrapper>,
     hold: Vec<KeyCodeWrapper>,
     tap: Vec<KeyCodeWrapper>,
+    ordered: bool,
+    unless: Vec<KeyCodeWrapper>,
 }
 
-impl Into<Mapping> for DualRoleConfig {
-    fn into(self) -> Mapping {
+impl From<DualRoleConfig> for Mapping {
+    fn from(config: DualRoleConfig) -> Self {
         Mapping::DualRole {
-            input: self.input.into(),
-            hold: self.hold.into_iter().map(Into::into).collect(),
-            tap: self.tap.into_iter().map(Into::into).collect(),
+            input: config.input.into_iter().map(Into::into).collect(),
+            hold: config.hold.into_iter().map(Into::into).collect(),
+            tap: config.tap.into_iter().map(Into::into).collect(),
+            ordered: config.ordered,
+            unless: config.unless.into_iter().map(Into::into).collect(),
         }
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone)]
 struct RemapConfig {
     input: Vec<KeyCodeWrapper>,
     output: Vec<KeyCodeWrapper>,
+    ordered: bool,
+    modifiers: ModifierPolicy,
+    mask: Vec<KeyCodeWrapper>,
+    unless: Vec<KeyCodeWrapper>,
+    exact: bool,
+    suppress: bool,
 }
 
-impl Into<Mapping> for RemapConfig {
-    fn into(self) -> Mapping {
+impl From<RemapConfig> for Mapping {
+    fn from(config: RemapConfig) -> Self {
         Mapping::Remap {
-            input: self.input.into_iter().map(Into::into).collect(),
-            output: self.output.into_iter().map(Into::into).collect(),
+            input: config.input.into_iter().collect(),
+            output: config.output,
+            ordered: config.ordered,
+            modifiers: config.modifiers,
+            mask: config.mask.into_iter().map(Into::into).collect(),
+            unless: config.unless.into_iter().map(Into::into).collect(),
+            exact: config.exact,
+            suppress: config.suppress,
+        }
+    }
+}
+
+/// A `[[dual_role]]` entry before its key names have been parsed
+#[derive(Debug, Deserialize)]
+struct DualRoleEntry {
+    input: KeyName,
+    hold: Vec<KeyName>,
+    tap: Vec<KeyName>,
+    #[serde(default)]
+    ordered: bool,
+    #[serde(default)]
+    unless: Vec<KeyName>,
+}
+
+impl DualRoleEntry {
+    fn resolve(&self, resolver: &Resolver) -> anyhow::Result<DualRoleConfig> {
+        Ok(DualRoleConfig {
+            input: resolver.chord(&self.input)?,
+            hold: resolver.output_keys(&self.hold)?,
+            tap: resolver.output_keys(&self.tap)?,
+            ordered: self.ordered,
+            unless: resolver.unless_keys(&self.unless)?,
+        })
+    }
+}
+
+/// The `[kill_switch]` section before its key names have been parsed
+#[derive(Debug, Deserialize)]
+struct KillSwitchEntry {
+    #[serde(default)]
+    keys: Option<KeyName>,
+    #[serde(default)]
+    action: KillSwitchAction,
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl KillSwitchEntry {
+    /// Returns None if the kill switch is disabled
+    fn resolve(&self, resolver: &Resolver) -> anyhow::Result<Option<KillSwitch>> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let names = match &self.keys {
+            Some(names) => names,
+            None => anyhow::bail!(
+                "{}: the [kill_switch] section needs `keys`, or `enabled = false` to disable it",
+                resolver.path.display()
+            ),
+        };
+        let keys = resolver.chord(names)?;
+        // The kill switch is matched against the codes that the input
+        // devices report, before scancodes are translated
+        if let Some(key) = keys
+            .iter()
+            .find(|k| !matches!(k.code, EventCode::EV_KEY(_)))
+        {
+            return Err(resolver.error_at(
+                names,
+                format!("{} cannot be part of the kill switch; only keys can", key),
+            ));
+        }
+        if keys.len() < 2 {
+            return Err(resolver.error_at(
+                names,
+                "the kill switch must be a chord of at least two keys, \
+                 so that it isn't pressed by accident",
+            ));
+        }
+        Ok(Some(KillSwitch {
+            keys: keys.into_iter().map(|k| k.code).collect(),
+            action: self.action,
+        }))
+    }
+}
+
+/// A `[[remap]]` entry before its key names have been parsed
+#[derive(Debug, Deserialize)]
+struct RemapEntry {
+    input: Vec<KeyName>,
+    output: Vec<KeyName>,
+    #[serde(default)]
+    ordered: bool,
+    #[serde(default)]
+    modifiers: ModifierPolicy,
+    #[serde(default)]
+    mask: Vec<KeyName>,
+    #[serde(default)]
+    unless: Vec<KeyName>,
+    #[serde(default)]
+    exact: bool,
+    #[serde(default)]
+    suppress: bool,
+}
+
+impl RemapEntry {
+    /// Resolve the entry into a rule for each combination of the
+    /// keys of the groups in its input
+    fn resolve(&self, resolver: &Resolver) -> anyhow::Result<Vec<RemapConfig>> {
+        let output = resolver.output_keys(&self.output)?;
+        let mask = resolver.mask_keys(&self.mask)?;
+        let unless = resolver.unless_keys(&self.unless)?;
+        Ok(resolver
+            .input_combinations(&self.input)?
+            .into_iter()
+            .map(|input| RemapConfig {
+                input,
+                output: output.clone(),
+                ordered: self.ordered,
+                modifiers: self.modifiers,
+                mask: mask.clone(),
+                unless: unless.clone(),
+                exact: self.exact,
+                suppress: self.suppress,
+            })
+            .collect())
+    }
+}
+
+/// A `[[map_range]]` entry, which maps each key of the `from` group to
+/// the key at the same position in the `to` group while the keys in
+/// `with` are held
+#[derive(Debug, Deserialize)]
+struct MapRangeEntry {
+    from: KeyName,
+    to: KeyName,
+    #[serde(default)]
+    with: Vec<KeyName>,
+    #[serde(default)]
+    modifiers: ModifierPolicy,
+    #[serde(default)]
+    unless: Vec<KeyName>,
+}
+
+impl MapRangeEntry {
+    fn resolve(&self, resolver: &Resolver) -> anyhow::Result<Vec<RemapConfig>> {
+        let from = resolver.group(&self.from)?;
+        let mut to = vec![];
+        for key in resolver.group(&self.to)? {
+            to.push(resolver.output_key(&self.to, key)?);
+        }
+        if from.len() != to.len() {
+            return Err(resolver.error_at(
+                &self.to,
+                format!("`from` has {} keys but `to` has {}", from.len(), to.len()),
+            ));
         }
+        let with = resolver.keys(&self.with)?;
+        let unless = resolver.unless_keys(&self.unless)?;
+        Ok(from
+            .into_iter()
+            .zip(to)
+            .map(|(from, to)| {
+                let mut input = with.clone();
+                input.push(from);
+                RemapConfig {
+                    input,
+                    output: vec![to],
+                    ordered: false,
+                    modifiers: self.modifiers,
+                    mask: vec![],
+                    unless: unless.clone(),
+                    exact: false,
+                    suppress: false,
+                }
+            })
+            .collect())
     }
 }
 
 #[derive(Debug, Deserialize)]
 struct ConfigFile {
-    device_name: String,
+    #[serde(default)]
+    device_name: Option<String>,
     #[serde(default)]
     phys: Option<String>,
 
     #[serde(default)]
-    dual_role: Vec<DualRoleConfig>,
+    group_siblings: Option<bool>,
+
+    #[serde(default)]
+    watchdog: Option<WatchdogConfig>,
+
+    #[serde(default)]
+    kill_switch: Option<KillSwitchEntry>,
 
     #[serde(default)]
-    remap: Vec<RemapConfig>,
+    runtime: Option<RuntimeConfig>,
+
+    #[serde(default)]
+    privileges: Option<PrivilegesConfig>,
+
+    #[serde(default)]
+    sandbox: Option<SandboxConfig>,
+
+    #[serde(default)]
+    include: Vec<String>,
+
+    /// Keys that count as modifiers in addition to the built-in ones
+    #[serde(default)]
+    modifier_keys: Vec<KeyName>,
+
+    /// Maps the name of each virtual modifier to its keys
+    #[serde(default)]
+    virtual_modifiers: BTreeMap<Spanned<String>, Vec<KeyName>>,
+
+    /// Maps the name of each group to its keys
+    #[serde(default)]
+    groups: BTreeMap<Spanned<String>, KeyName>,
+
+    #[serde(default)]
+    dual_role: Vec<DualRoleEntry>,
+
+    #[serde(default)]
+    remap: Vec<Spanned<RemapEntry>>,
+
+    #[serde(default)]
+    map_range: Vec<Spanned<MapRangeEntry>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write `files` to a fresh directory and load its `main.toml`,
+    /// returning the error, if any, with the directory left out
+    fn load(name: &str, files: &[(&str, &str)]) -> Result<MappingConfig, String> {
+        let dir =
+            std::env::temp_dir().join(format!("evremap-config-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (file, toml) in files {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, toml).unwrap();
+        }
+        let config = MappingConfig::from_file(dir.join("main.toml"))
+            .map_err(|err| format!("{:#}", err).replace(&format!("{}/", dir.display()), ""));
+        std::fs::remove_dir_all(&dir).unwrap();
+        config
+    }
+
+    fn origins(config: &MappingConfig) -> Vec<String> {
+        config
+            .origins
+            .iter()
+            .map(|o| {
+                let file = o.file.file_name().unwrap().to_string_lossy();
+                format!("{}: {} #{}", file, o.section, o.index)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn includes_and_fragments_merge_in_order() {
+        let config = load(
+            "order",
+            &[
+                (
+                    "main.toml",
+                    r#"
+                    device_name = "main"
+                    include = ["common.toml"]
+                    [[remap]]
+                    input = ["KEY_B"]
+                    output = ["KEY_2"]
+                    "#,
+                ),
+                (
+                    "common.toml",
+                    r#"
+                    device_name = "common"
+                    [[remap]]
+                    input = ["KEY_A"]
+                    output = ["KEY_1"]
+                    "#,
+                ),
+                (
+                    "main.d/20-host.toml",
+                    r#"
+                    device_name = "host"
+                    [[remap]]
+                    input = ["KEY_D"]
+                    output = ["KEY_4"]
+                    "#,
+                ),
+                (
+                    "main.d/10-base.toml",
+                    r#"
+                    [[remap]]
+                    input = ["KEY_C"]
+                    output = ["KEY_3"]
+                    "#,
+                ),
+                ("main.d/30-ignored.toml~", "this is not toml"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.device_name, "host");
+        assert_eq!(
+            origins(&config),
+            [
+                "common.toml: remap #1",
+                "main.toml: remap #1",
+                "10-base.toml: remap #1",
+                "20-host.toml: remap #1",
+            ]
+        );
+    }
+
+    #[test]
+    fn conflicting_rules_name_both_files() {
+        let err = load(
+            "remap-conflict",
+            &[
+                (
+                    "main.toml",
+                    r#"
+                    device_name = "test"
+                    include = ["common.toml"]
+                    [[remap]]
+                    input = ["CAPSLOCK"]
+                    output = ["ESC"]
+                    "#,
+                ),
+                (
+                    "common.toml",
+                    r#"
+                    [[remap]]
+                    input = ["CAPSLOCK"]
+                    output = ["LEFTCTRL"]
+                    "#,
+                ),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "remap for [KEY_CAPSLOCK] in main.toml conflicts with the one in common.toml"
+        );
+
+        let err = load(
+            "dual-role-conflict",
+            &[
+                (
+                    "main.toml",
+                    r#"
+                    device_name = "test"
+                    [[dual_role]]
+                    input = "CAPSLOCK"
+                    hold = ["LEFTCTRL"]
+                    tap = ["ESC"]
+                    "#,
+                ),
+                (
+                    "main.d/host.toml",
+                    r#"
+                    [[dual_role]]
+                    input = "CAPSLOCK"
+                    hold = ["LEFTALT"]
+                    tap = ["ESC"]
+                    "#,
+                ),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "dual_role for KEY_CAPSLOCK in main.d/host.toml conflicts with the one in main.toml"
+        );
+    }
+
+    #[test]
+    fn conflicting_definitions_name_both_files() {
+        let err = load(
+            "virtual-conflict",
+            &[
+                (
+                    "main.toml",
+                    r#"
+                    device_name = "test"
+                    include = ["common.toml"]
+                    [virtual_modifiers]
+                    hyper = ["LEFTCTRL", "LEFTALT"]
+                    "#,
+                ),
+                (
+                    "common.toml",
+                    r#"
+                    [virtual_modifiers]
+                    hyper = ["LEFTCTRL", "LEFTSHIFT"]
+                    "#,
+                ),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "virtual modifier HYPER in main.toml conflicts with the one in common.toml"
+        );
+
+        let err = load(
+            "group-conflict",
+            &[
+                (
+                    "main.toml",
+                    r#"
+                    device_name = "test"
+                    [groups]
+                    arrows = "up down"
+                    "#,
+                ),
+                (
+                    "main.d/host.toml",
+                    r#"
+                    [groups]
+                    arrows = "left right"
+                    "#,
+                ),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "group @arrows in main.d/host.toml conflicts with the one in main.toml"
+        );
+    }
+
+    #[test]
+    fn repeated_rules_do_not_conflict() {
+        let common = r#"
+            [groups]
+            arrows = "up down"
+            [[remap]]
+            input = ["CAPSLOCK"]
+            output = ["ESC"]
+        "#;
+        let config = load(
+            "repeated",
+            &[
+                (
+                    "main.toml",
+                    &format!("device_name = \"test\"\ninclude = [\"common.toml\"]\n{common}"),
+                ),
+                ("common.toml", common),
+                // Including a file twice only loads it once
+                ("main.d/host.toml", "include = [\"../common.toml\"]"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            origins(&config),
+            ["common.toml: remap #1", "main.toml: remap #1"]
+        );
+    }
+
+    #[test]
+    fn include_errors() {
+        let err = load(
+            "cycle",
+            &[
+                (
+                    "main.toml",
+                    "device_name = \"test\"\ninclude = [\"a.toml\"]",
+                ),
+                ("a.toml", "include = [\"main.toml\"]"),
+            ],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "included from main.toml: included from a.toml: main.toml includes itself"
+        );
+
+        let err = load(
+            "missing",
+            &[(
+                "main.toml",
+                "device_name = \"test\"\ninclude = [\"a.toml\"]",
+            )],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "expanding include `a.toml` in main.toml: a.toml does not exist"
+        );
+
+        // A wildcard may match nothing
+        load(
+            "wildcard",
+            &[(
+                "main.toml",
+                "device_name = \"test\"\ninclude = [\"hosts/*.toml\"]",
+            )],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn suggestions() {
+        for (typo, suggestion) in [
+            ("leftctrl", Some("KEY_LEFTCTRL")),
+            ("Key_Esc", Some("KEY_ESC")),
+            ("KEY_LEFTCRTL", Some("KEY_LEFTCTRL")),
+            ("capslok", Some("KEY_CAPSLOCK")),
+            ("REL_WHEL", Some("REL_WHEEL")),
+            ("KEY", None),
+            ("qqqqqqqq", None),
+        ] {
+            assert_eq!(suggest_key(typo).as_deref(), suggestion, "{}", typo);
+        }
+    }
+
+    #[test]
+    fn bad_names_are_errors() {
+        for (name, err) in [
+            ("KEY", "Invalid key `KEY`.  Use `evremap list-keys` to see possible keys."),
+            (
+                "REL_X+abc",
+                "Invalid scale `+abc` for `REL_X`.  The scale must be a whole number, eg: `REL_X+2`",
+            ),
+            (
+                "KEY_0x300",
+                "`KEY_0x300` is out of range; the highest code of its type is 0x2ff",
+            ),
+            ("KEY_A\0", "Invalid key `KEY_A\0`.  Did you mean KEY_A?"),
+        ] {
+            assert_eq!(
+                KeyCodeWrapper::from_name(name).unwrap_err().to_string(),
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn errors_point_at_the_key() {
+        let err = load(
+            "span",
+            &[(
+                "main.toml",
+                "device_name = \"test\"\n\n[[remap]]\ninput = [\"CAPSLOCK\"]\noutput = [\"LEFTCTRLL\"]\n",
+            )],
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            "main.toml:5:11: Invalid key `LEFTCTRLL`.  Did you mean KEY_LEFTCTRL?\n  \
+             |\n\
+             5 | output = [\"LEFTCTRLL\"]\n  \
+             |           ^^^^^^^^^^^"
+        );
+    }
+
+    #[test]
+    fn error_columns_count_characters() {
+        let source = "# ÄÖÜ\nkey = \"ü\", \"KEY_Q\"";
+        let start = source.find("\"KEY_Q\"").unwrap();
+        let err = error_at(Path::new("x.toml"), source, start..start + 7, "bad");
+        assert_eq!(
+            err.to_string(),
+            "x.toml:2:12: bad\n  |\n2 | key = \"ü\", \"KEY_Q\"\n  |            ^^^^^^^"
+        );
+        // A span that runs past the end of its line is cut short
+        let err = error_at(Path::new("x.toml"), source, 0..source.len(), "bad");
+        assert_eq!(
+            err.to_string(),
+            "x.toml:1:1: bad\n  |\n1 | # ÄÖÜ\n  | ^^^^^"
+        );
+    }
+
+    /// The remap rules of `config`, eg: `KEY_LEFTALT+KEY_F1 -> KEY_F13`
+    fn remaps(config: &MappingConfig) -> Vec<String> {
+        config
+            .mappings
+            .iter()
+            .filter_map(|map| match map {
+                Mapping::Remap { input, output, .. } => {
+                    let mut input: Vec<String> = input.iter().map(|k| k.to_string()).collect();
+                    input.sort();
+                    let output: Vec<String> = output.iter().map(|k| k.to_string()).collect();
+                    Some(format!("{} -> {}", input.join("+"), output.join("+")))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn map_range_pairs_keys_in_order() {
+        let config = load(
+            "map-range",
+            &[(
+                "main.toml",
+                r#"
+                device_name = "test"
+                [groups]
+                fkeys = "KEY_F1..KEY_F3"
+
+                [[remap]]
+                input = ["KEY_A"]
+                output = ["KEY_B"]
+
+                [[map_range]]
+                with = ["KEY_LEFTALT"]
+                from = "@fkeys"
+                to = "KEY_F13..KEY_F15"
+
+                [[remap]]
+                input = ["KEY_C"]
+                output = ["KEY_D"]
+
+                [[map_range]]
+                from = "KEY_9..KEY_MINUS"
+                to = "KEY_X..KEY_Z"
+                "#,
+            )],
+        )
+        .unwrap();
+        assert_eq!(
+            remaps(&config),
+            [
+                "KEY_A -> KEY_B",
+                "KEY_F1+KEY_LEFTALT -> KEY_F13",
+                "KEY_F2+KEY_LEFTALT -> KEY_F14",
+                "KEY_F3+KEY_LEFTALT -> KEY_F15",
+                "KEY_C -> KEY_D",
+                "KEY_9 -> KEY_X",
+                "KEY_0 -> KEY_Y",
+                "KEY_MINUS -> KEY_Z",
+            ]
+        );
+        assert_eq!(
+            origins(&config),
+            [
+                "main.toml: remap #1",
+                "main.toml: map_range #1",
+                "main.toml: map_range #1",
+                "main.toml: map_range #1",
+                "main.toml: remap #2",
+                "main.toml: map_range #2",
+                "main.toml: map_range #2",
+                "main.toml: map_range #2",
+            ]
+        );
+    }
+
+    #[test]
+    fn map_range_errors() {
+        let map_range = |name: &str, from: &str, to: &str| {
+            let toml = format!(
+                "device_name = \"test\"\n[[map_range]]\nfrom = \"{}\"\nto = \"{}\"\n",
+                from, to
+            );
+            load(name, &[("main.toml", &toml)]).unwrap_err()
+        };
+        assert_eq!(
+            map_range("map-range-length", "KEY_F1..KEY_F12", "KEY_F13..KEY_F20"),
+            "main.toml:4:6: `from` has 12 keys but `to` has 8\n  \
+             |\n\
+             4 | to = \"KEY_F13..KEY_F20\"\n  \
+             |      ^^^^^^^^^^^^^^^^^^"
+        );
+        assert_eq!(
+            map_range("map-range-reversed", "KEY_F12..KEY_F1", "KEY_F13..KEY_F24")
+                .lines()
+                .next()
+                .unwrap(),
+            "main.toml:3:8: Invalid range `KEY_F12..KEY_F1`: KEY_F12 comes after KEY_F1"
+        );
+        assert_eq!(
+            map_range("map-range-group", "@nope", "KEY_F13")
+                .lines()
+                .next()
+                .unwrap(),
+            "main.toml:3:8: Unknown group `@nope`.  Groups are defined in the `[groups]` table."
+        );
+        assert_eq!(
+            map_range("map-range-open", "KEY_A KEY_B", "KEY_X..")
+                .lines()
+                .next()
+                .unwrap(),
+            "main.toml:4:6: Invalid range `KEY_X..`: a range needs a first and a last key"
+        );
+    }
+
+    #[test]
+    fn kill_switch_is_pressed_by_the_last_key_of_the_chord() {
+        let kill_switch = KillSwitch::fallback();
+        let (lshift, rshift, esc) = (
+            KeyCode::EV_KEY(EV_KEY::KEY_LEFTSHIFT),
+            KeyCode::EV_KEY(EV_KEY::KEY_RIGHTSHIFT),
+            KeyCode::EV_KEY(EV_KEY::KEY_ESC),
+        );
+        let a = KeyCode::EV_KEY(EV_KEY::KEY_A);
+        let held: HashSet<KeyCode> = [lshift, rshift, esc].into();
+        // Whichever key completes the chord
+        assert!(kill_switch.pressed_by(&esc, &held));
+        assert!(kill_switch.pressed_by(&lshift, &held));
+        // Other keys don't, even while the chord is held
+        let with_a: HashSet<KeyCode> = [lshift, rshift, esc, a].into();
+        assert!(!kill_switch.pressed_by(&a, &with_a));
+        // Nor does part of the chord
+        let partial: HashSet<KeyCode> = [lshift, esc].into();
+        assert!(!kill_switch.pressed_by(&esc, &partial));
+    }
+
+    #[test]
+    fn kill_switch_configuration() {
+        let config = load(
+            "kill-switch-fallback",
+            &[("main.toml", "device_name = \"test\"")],
+        );
+        assert_eq!(config.unwrap().kill_switch, Some(KillSwitch::fallback()));
+
+        let config = load(
+            "kill-switch-configured",
+            &[(
+                "main.toml",
+                r#"
+                device_name = "test"
+                [kill_switch]
+                keys = "KEY_LEFTCTRL+KEY_BACKSPACE"
+                action = "exit"
+                "#,
+            )],
+        );
+        assert_eq!(
+            config.unwrap().kill_switch,
+            Some(KillSwitch {
+                keys: vec![
+                    KeyCode::EV_KEY(EV_KEY::KEY_LEFTCTRL),
+                    KeyCode::EV_KEY(EV_KEY::KEY_BACKSPACE),
+                ],
+                action: KillSwitchAction::Exit,
+            })
+        );
+
+        let config = load(
+            "kill-switch-disabled",
+            &[(
+                "main.toml",
+                "device_name = \"test\"\n[kill_switch]\nenabled = false\n",
+            )],
+        );
+        assert_eq!(config.unwrap().kill_switch, None);
+
+        let err = load(
+            "kill-switch-no-keys",
+            &[(
+                "main.toml",
+                "device_name = \"test\"\n[kill_switch]\naction = \"exit\"\n",
+            )],
+        );
+        assert_eq!(
+            err.unwrap_err(),
+            "main.toml: the [kill_switch] section needs `keys`, \
+             or `enabled = false` to disable it"
+        );
+    }
 }
@@ -0,0 +1,205 @@
+use crate::mapping::PrivilegesConfig;
+use anyhow::{Context, Result};
+use std::ffi::CString;
+
+/// The arguments of the capget and capset system calls, which libc
+/// doesn't wrap
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x20080522;
+
+fn check(res: libc::c_int, what: impl FnOnce() -> String) -> Result<()> {
+    if res != 0 {
+        return Err(std::io::Error::last_os_error()).context(what());
+    }
+    Ok(())
+}
+
+/// Returns the uid and the primary gid of the user called `name`
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = CString::new(name)?;
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    let res = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut pwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::from_raw_os_error(res))
+            .context(format!("looking up user `{}`", name));
+    }
+    if result.is_null() {
+        anyhow::bail!("there is no user called `{}`", name);
+    }
+    Ok((pwd.pw_uid, pwd.pw_gid))
+}
+
+fn lookup_group(name: &str) -> Result<libc::gid_t> {
+    let c_name = CString::new(name)?;
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut result = std::ptr::null_mut();
+    let res = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::from_raw_os_error(res))
+            .context(format!("looking up group `{}`", name));
+    }
+    if result.is_null() {
+        anyhow::bail!("there is no group called `{}`", name);
+    }
+    Ok(grp.gr_gid)
+}
+
+/// Returns the uid and gid that `config` names
+fn resolve(config: &PrivilegesConfig) -> Result<(libc::uid_t, libc::gid_t)> {
+    let (uid, user_gid) = lookup_user(&config.user)?;
+    if uid == 0 {
+        anyhow::bail!("the [privileges] user must not be root");
+    }
+    let gid = match &config.group {
+        Some(group) => lookup_group(group)?,
+        None => user_gid,
+    };
+    Ok((uid, gid))
+}
+
+/// Switch to the user and group of `config`, with no supplementary
+/// groups and no capabilities, and make sure that nothing that is
+/// executed later can gain privileges.  The devices that are already
+/// open stay usable.
+pub fn drop_privileges(config: &PrivilegesConfig) -> Result<()> {
+    let (uid, gid) = resolve(config)?;
+
+    // Already running as the user, eg: started by systemd with `User=`
+    // and some capabilities; there is nothing to switch, but the
+    // supplementary groups that opened the devices are still dropped
+    let switch = unsafe { libc::geteuid() != uid || libc::getegid() != gid };
+    if switch || unsafe { libc::getgroups(0, std::ptr::null_mut()) } != 0 {
+        check(unsafe { libc::setgroups(0, std::ptr::null()) }, || {
+            "clearing the supplementary groups; without CAP_SETGID, \
+             start evremap without any"
+                .to_string()
+        })?;
+    }
+    if switch {
+        check(unsafe { libc::setresgid(gid, gid, gid) }, || {
+            format!("switching to group {}", gid)
+        })?;
+        check(unsafe { libc::setresuid(uid, uid, uid) }, || {
+            format!("switching to user `{}`", config.user)
+        })?;
+    }
+
+    // Switching away from root clears the capabilities, but not those
+    // that were granted to a non-root user
+    check(
+        unsafe {
+            libc::prctl(
+                libc::PR_CAP_AMBIENT,
+                libc::PR_CAP_AMBIENT_CLEAR_ALL,
+                0,
+                0,
+                0,
+            )
+        },
+        || "clearing the ambient capabilities".to_string(),
+    )?;
+    let mut header = CapHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let data = [CapData::default(), CapData::default()];
+    check(
+        unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) as libc::c_int },
+        || "clearing the capabilities".to_string(),
+    )?;
+    check(
+        unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) },
+        || "setting PR_SET_NO_NEW_PRIVS".to_string(),
+    )?;
+
+    if unsafe { libc::setuid(0) } == 0 {
+        anyhow::bail!("still able to switch back to root after dropping privileges");
+    }
+    log::info!(
+        "Dropped privileges; now running as user `{}` and group {}",
+        config.user,
+        gid
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn privileges(user: &str, group: Option<&str>) -> PrivilegesConfig {
+        PrivilegesConfig {
+            user: user.to_string(),
+            group: group.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn users_and_groups_are_looked_up() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_group("root").unwrap(), 0);
+        assert_eq!(
+            lookup_user("evremap-no-such-user").unwrap_err().to_string(),
+            "there is no user called `evremap-no-such-user`"
+        );
+        assert_eq!(
+            lookup_group("evremap-no-such-group")
+                .unwrap_err()
+                .to_string(),
+            "there is no group called `evremap-no-such-group`"
+        );
+    }
+
+    #[test]
+    fn root_is_rejected() {
+        assert_eq!(
+            resolve(&privileges("root", None)).unwrap_err().to_string(),
+            "the [privileges] user must not be root"
+        );
+        // Whatever the group
+        assert_eq!(
+            resolve(&privileges("root", Some("nogroup")))
+                .unwrap_err()
+                .to_string(),
+            "the [privileges] user must not be root"
+        );
+        assert_eq!(
+            resolve(&privileges("evremap-no-such-user", Some("root")))
+                .unwrap_err()
+                .to_string(),
+            "there is no user called `evremap-no-such-user`"
+        );
+    }
+}
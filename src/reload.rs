@@ -0,0 +1,320 @@
+use crate::mapping::*;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+
+/// Watches for requests to reload the configuration file.
+/// A reload is requested by sending SIGHUP to the process, and
+/// optionally whenever the file, the files that it includes or
+/// its fragment directory are modified.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    device_name: String,
+    phys: Option<String>,
+    group_siblings: bool,
+    signal_fd: OwnedFd,
+    inotify_fd: Option<OwnedFd>,
+    /// Maps inotify watch descriptors to the watched directory
+    watches: HashMap<i32, PathBuf>,
+    files: Vec<PathBuf>,
+    fragment_dir: PathBuf,
+}
+
+fn check_fd(fd: RawFd, what: &str) -> Result<OwnedFd> {
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context(what.to_string());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Block `signals` for the process and return a signalfd through
+/// which they are delivered instead
+pub fn signal_fd(signals: &[libc::c_int]) -> Result<OwnedFd> {
+    unsafe {
+        let mut mask: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut mask);
+        for signal in signals {
+            libc::sigaddset(&mut mask, *signal);
+        }
+        if libc::pthread_sigmask(libc::SIG_BLOCK, &mask, std::ptr::null_mut()) != 0 {
+            bail!("failed to block signals {:?}", signals);
+        }
+        check_fd(
+            libc::signalfd(-1, &mask, libc::SFD_NONBLOCK | libc::SFD_CLOEXEC),
+            "creating signalfd",
+        )
+    }
+}
+
+impl ConfigWatcher {
+    /// Begin watching `path`, which was loaded as `config`.
+    /// SIGHUP is blocked for the process and delivered via a signalfd
+    /// instead; when `watch_file` is true the directories containing its
+    /// files are also watched via inotify, so that editors which replace
+    /// a file rather than rewriting it in place are noticed too.
+    pub fn new(path: &Path, config: &MappingConfig, watch_file: bool) -> Result<Self> {
+        let signal_fd = signal_fd(&[libc::SIGHUP]).context("handling SIGHUP")?;
+
+        let inotify_fd = if watch_file {
+            Some(check_fd(
+                unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) },
+                "creating inotify instance",
+            )?)
+        } else {
+            None
+        };
+
+        let mut watcher = Self {
+            path: path.to_path_buf(),
+            device_name: config.device_name.clone(),
+            phys: config.phys.clone(),
+            group_siblings: config.group_siblings,
+            signal_fd,
+            inotify_fd,
+            watches: HashMap::new(),
+            files: vec![],
+            fragment_dir: config.fragment_dir.clone(),
+        };
+        watcher.watch_files(config)?;
+        Ok(watcher)
+    }
+
+    /// Watch the directories holding each of the files that make up
+    /// `config`, as well as its fragment directory
+    fn watch_files(&mut self, config: &MappingConfig) -> Result<()> {
+        self.files = config
+            .files
+            .iter()
+            .filter_map(|f| Some(dir_of(f).join(f.file_name()?)))
+            .collect();
+        let inotify_fd = match &self.inotify_fd {
+            Some(fd) => fd.as_raw_fd(),
+            None => return Ok(()),
+        };
+        let mut dirs: Vec<&Path> = config.files.iter().map(|f| dir_of(f)).collect();
+        if config.fragment_dir.is_dir() {
+            dirs.push(&config.fragment_dir);
+        }
+        for dir in dirs {
+            if self.watches.values().any(|watched| watched == dir) {
+                continue;
+            }
+            let dir_name = CString::new(dir.as_os_str().as_bytes())?;
+            let wd = unsafe {
+                libc::inotify_add_watch(
+                    inotify_fd,
+                    dir_name.as_ptr(),
+                    libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_CREATE | libc::IN_DELETE,
+                )
+            };
+            if wd < 0 {
+                return Err(std::io::Error::last_os_error())
+                    .context(format!("watching {}", dir.display()));
+            }
+            self.watches.insert(wd, dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    /// The descriptors that become readable when a reload may be due
+    pub fn fds(&self) -> Vec<RawFd> {
+        let mut fds = vec![self.signal_fd.as_raw_fd()];
+        if let Some(fd) = &self.inotify_fd {
+            fds.push(fd.as_raw_fd());
+        }
+        fds
+    }
+
+    /// Consume the pending notifications on `fd`, returning true
+    /// if they amount to a request to reload the configuration.
+    pub fn consume(&mut self, fd: RawFd) -> Result<bool> {
+        let mut buf = [0u8; 4096];
+        let mut reload = false;
+        loop {
+            let len = unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if len < 0 {
+                let err = std::io::Error::last_os_error();
+                match err.kind() {
+                    std::io::ErrorKind::WouldBlock => return Ok(reload),
+                    std::io::ErrorKind::Interrupted => continue,
+                    _ => return Err(err).context("reading reload notifications"),
+                }
+            }
+            if len == 0 {
+                return Ok(reload);
+            }
+            if fd == self.signal_fd.as_raw_fd() {
+                log::info!("Received SIGHUP");
+                reload = true;
+            } else if let Some(changed) = self.changed_config_file(&buf[..len as usize]) {
+                log::info!("{} changed", changed.display());
+                reload = true;
+            }
+        }
+    }
+
+    /// Returns the first file named by the inotify events in `buf`
+    /// that is part of the configuration
+    fn changed_config_file(&self, mut buf: &[u8]) -> Option<PathBuf> {
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut found = None;
+        while buf.len() >= header {
+            let event: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const _) };
+            let end = (header + event.len as usize).min(buf.len());
+            let name = &buf[header..end];
+            let name = match name.iter().position(|&b| b == 0) {
+                Some(nul) => &name[..nul],
+                None => name,
+            };
+            buf = &buf[end..];
+
+            let dir = match self.watches.get(&event.wd) {
+                Some(dir) => dir,
+                None => continue,
+            };
+            let changed = dir.join(OsStr::from_bytes(name));
+            let is_fragment = *dir == self.fragment_dir
+                && changed
+                    .extension()
+                    .map(|ext| ext == "toml")
+                    .unwrap_or(false);
+            if found.is_none() && (is_fragment || self.files.contains(&changed)) {
+                found = Some(changed);
+            }
+        }
+        found
+    }
+
+    /// Load and validate the configuration file
+    pub fn load(&mut self) -> Result<MappingConfig> {
+        let config = MappingConfig::from_file(&self.path).context(format!(
+            "loading MappingConfig from {}",
+            self.path.display()
+        ))?;
+        if config.device_name != self.device_name
+            || config.phys != self.phys
+            || config.group_siblings != self.group_siblings
+        {
+            log::warn!(
+                "The device selection in {} has changed; \
+                 restart evremap for that to take effect",
+                self.path.display()
+            );
+        }
+        self.watch_files(&config)?;
+        Ok(config)
+    }
+}
+
+fn dir_of(path: &Path) -> &Path {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A watcher for /etc/evremap/main.toml, which includes
+    /// common.toml, with the fragments in /etc/evremap/main.d
+    fn watcher() -> ConfigWatcher {
+        let null = std::fs::File::open("/dev/null").unwrap();
+        ConfigWatcher {
+            path: PathBuf::from("/etc/evremap/main.toml"),
+            device_name: "test".to_string(),
+            phys: None,
+            group_siblings: false,
+            signal_fd: OwnedFd::from(null),
+            inotify_fd: None,
+            watches: HashMap::from([
+                (1, PathBuf::from("/etc/evremap")),
+                (2, PathBuf::from("/etc/evremap/main.d")),
+            ]),
+            files: vec![
+                PathBuf::from("/etc/evremap/main.toml"),
+                PathBuf::from("/etc/evremap/common.toml"),
+            ],
+            fragment_dir: PathBuf::from("/etc/evremap/main.d"),
+        }
+    }
+
+    /// Encode an inotify event the way the kernel does, with the name
+    /// padded by NULs to a multiple of the header alignment
+    fn event(wd: i32, name: &str) -> Vec<u8> {
+        let padded = (name.len() + 1).next_multiple_of(4);
+        let mut buf = vec![];
+        buf.extend_from_slice(&wd.to_ne_bytes());
+        buf.extend_from_slice(&libc::IN_CLOSE_WRITE.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&(padded as u32).to_ne_bytes());
+        buf.extend_from_slice(name.as_bytes());
+        buf.resize(buf.len() + padded - name.len(), 0);
+        buf
+    }
+
+    fn changed(events: &[(i32, &str)]) -> Option<PathBuf> {
+        let buf: Vec<u8> = events
+            .iter()
+            .flat_map(|(wd, name)| event(*wd, name))
+            .collect();
+        watcher().changed_config_file(&buf)
+    }
+
+    #[test]
+    fn config_files_are_noticed() {
+        assert_eq!(
+            changed(&[(1, "main.toml")]),
+            Some(PathBuf::from("/etc/evremap/main.toml"))
+        );
+        assert_eq!(
+            changed(&[(1, "common.toml")]),
+            Some(PathBuf::from("/etc/evremap/common.toml"))
+        );
+        assert_eq!(
+            changed(&[(2, "50-laptop.toml")]),
+            Some(PathBuf::from("/etc/evremap/main.d/50-laptop.toml"))
+        );
+    }
+
+    #[test]
+    fn other_files_are_ignored() {
+        // An editor's swap file next to the configuration
+        assert_eq!(changed(&[(1, ".main.toml.swp")]), None);
+        // A file that is not included
+        assert_eq!(changed(&[(1, "other.toml")]), None);
+        // Only .toml files in the fragment directory are fragments
+        assert_eq!(changed(&[(2, "50-laptop.toml~")]), None);
+        // A watch that has since been replaced
+        assert_eq!(changed(&[(3, "main.toml")]), None);
+    }
+
+    #[test]
+    fn the_first_config_file_in_a_batch_is_returned() {
+        assert_eq!(
+            changed(&[
+                (1, "4913"),
+                (1, "common.toml"),
+                (2, "10-desk.toml"),
+                (1, "main.toml"),
+            ]),
+            Some(PathBuf::from("/etc/evremap/common.toml"))
+        );
+    }
+
+    #[test]
+    fn truncated_events_are_not_misread() {
+        let mut buf = event(1, "other.toml");
+        buf.extend(event(1, "main.toml"));
+        // Cut the name of the second event short
+        buf.truncate(buf.len() - 8);
+        assert_eq!(watcher().changed_config_file(&buf), None);
+        // An incomplete header is ignored
+        assert_eq!(watcher().changed_config_file(&buf[..10]), None);
+    }
+}
@@ -1,11 +1,21 @@
 use crate::mapping::*;
+use crate::privileges::drop_privileges;
+use crate::reload::{signal_fd, ConfigWatcher};
+use crate::rules::{Keys, RuleSet};
+use crate::runtime::{configure_thread, LatencyLog};
+use crate::sandbox;
 use anyhow::*;
-use evdev_rs::{DeviceWrapper, Device, GrabMode, InputEvent, ReadFlag, TimeVal, UInputDevice};
-use evdev_rs::enums::EV_KEY;
-use std::cmp::Ordering;
+use evdev_rs::enums::{EV_ABS, EV_KEY, EV_MSC, EV_REL, EV_SYN};
+use evdev_rs::{
+    Device, DeviceWrapper, EnableCodeData, GrabMode, InputEvent, ReadFlag, TimeVal, UInputDevice,
+};
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
-use std::path::Path;
-use std::time::Duration;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 #[derive(Clone, Copy, Debug)]
 enum KeyEventType {
@@ -49,7 +59,7 @@ fn timeval_diff(newer: &TimeVal, older: &TimeVal) -> Duration {
     Duration::from_micros(((secs * MICROS_PER_SECOND) + usecs) as u64)
 }
 
-const fn to_event_type(code: &EventCode) -> EventType {
+fn to_event_type(code: &EventCode) -> EventType {
     match code {
         EventCode::EV_SYN(_n) => EventType::EV_SYN,
         EventCode::EV_KEY(_n) => EventType::EV_KEY,
@@ -64,340 +74,1292 @@ const fn to_event_type(code: &EventCode) -> EventType {
         EventCode::EV_PWR => EventType::EV_PWR,
         EventCode::EV_FF_STATUS(_n) => EventType::EV_FF_STATUS,
         EventCode::EV_MAX => EventType::EV_MAX,
-        _ => EventType::EV_UNK,
+        EventCode::EV_UNK { event_type, .. } => {
+            evdev_rs::enums::int_to_event_type(*event_type).unwrap_or(EventType::EV_UNK)
+        }
     }
 }
 
 pub struct InputMapper {
-    input: Device,
-    output: UInputDevice,
-    /// If present in this map, the key is down since the instant
-    /// of its associated value
-    input_state: HashMap<KeyCode, TimeVal>,
+    /// The grabbed input devices; the first is the one that was
+    /// configured and any others are its siblings
+    inputs: Vec<Device>,
+    /// The paths that `inputs` were opened from
+    input_paths: Vec<PathBuf>,
+    output: Box<dyn OutputDevice>,
+    /// The held keys, in the order that they were pressed
+    input_state: Vec<KeyCode>,
 
-    mappings: Vec<Mapping>,
+    /// Shared so that a rule can be used while the mapper is updated
+    rules: Rc<RuleSet>,
+    /// Where each of the mappings was defined
+    origins: Vec<RuleOrigin>,
     mapped_types: HashSet<EventType>,
+    /// Codes that the mappings can emit but that the input device
+    /// does not natively support; these were enabled for the output
+    added_codes: HashSet<KeyCode>,
+    config_watcher: Option<ConfigWatcher>,
+    /// Delivers the signals that ask the remapper to stop
+    shutdown_fd: OwnedFd,
+    watchdog: WatchdogConfig,
+    /// The configured kill switch, the fallback one, or None if it
+    /// is disabled
+    kill_switch: Option<KillSwitch>,
+    /// The key whose press completed the kill switch; as its press was
+    /// swallowed, so are its repeats and its release
+    swallowed_key: Option<KeyCode>,
+    /// The keys that are held on the inputs, as they report them;
+    /// the kill switch is recognized from these
+    physical_keys: HashSet<KeyCode>,
+    /// Set by the kill switch; every event is then passed through
+    /// unchanged instead of being mapped
+    passthrough: bool,
+    /// When the last input event was read; the watchdog checks for
+    /// stuck keys once the inputs have been idle for long enough
+    last_event: Instant,
+    /// How the thread that runs the mapper is scheduled
+    runtime: RuntimeConfig,
+    latency: LatencyLog,
+    /// The user to switch to before going into the read loop
+    privileges: Option<PrivilegesConfig>,
+    /// Once privileges are dropped, the output device can no longer
+    /// be recreated
+    dropped_privileges: bool,
+    /// How to restrict the remapper before going into the read loop
+    sandbox: Option<SandboxConfig>,
+    /// Every file that contributed to the configuration, and the
+    /// directory of its fragments; the sandbox allows them to be read
+    /// again when it is reloaded
+    config_files: Vec<PathBuf>,
+    fragment_dir: PathBuf,
 
     /// The most recent candidate for a tap function is held here
-    tapping: Option<KeyCode>,
+    tapping: Option<PendingTap>,
+
+    /// The `MSC_SCAN` scancodes that the mappings refer to
+    scan_codes: HashSet<u32>,
+    /// One of `scan_codes` that was reported in the current frame
+    /// and has not yet been matched with its key event
+    pending_scan: Option<u32>,
+    /// Maps held keys that were pressed with one of `scan_codes`
+    /// to the pseudo code that stands in for them
+    scanned_keys: HashMap<KeyCode, KeyCode>,
 
     output_keys: HashSet<KeyCode>,
+    /// The events written since the last `SYN_REPORT` of each input;
+    /// they are sent as one frame when the input's frame ends, except
+    /// that the keys of a tap, or those that an axis is mapped to, are
+    /// released in a frame after their press
+    frames: Vec<Vec<InputEvent>>,
+    /// The input whose frame is being processed; whatever the mapper
+    /// writes is added to its frame
+    frame_input: usize,
+    /// The keys that the mappings last asked for, in the order that
+    /// they were pressed; they are released in the reverse order
+    press_order: Vec<KeyCode>,
+    /// Which held keys the mappings consume or suppress, and the
+    /// buffers used to work out the output keys for each event
+    tracker: KeyTracker,
+}
+
+/// Why `InputMapper::run_mapper` returned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// SIGINT, SIGTERM or SIGQUIT was received
+    Signal,
+    /// The kill switch was pressed and its action is `exit`
+    KillSwitch,
+}
+
+/// A DualRole mapping whose input was pressed, and that becomes a tap
+/// if its input is released soon enough without other keys being used
+struct PendingTap {
+    input: Vec<KeyCode>,
+    tap: Vec<KeyCode>,
+    /// When the last key of `input` was pressed
+    since: TimeVal,
+}
+
+/// Works out the output keys for the held input keys.  This is done
+/// for every key event, so the buffers are kept to be reused rather
+/// than being allocated each time.
+#[derive(Default)]
+struct KeyTracker {
+    /// Held keys that the mappings last consumed
+    consumed: HashSet<KeyCode>,
+    /// Held keys that were consumed by a rule that no longer applies;
+    /// these are ignored until they are released
+    suppressed: HashSet<KeyCode>,
+    /// Keys that were already held when the inputs were grabbed; these
+    /// stay suppressed until they are released, even across a reload
+    held_at_grab: HashSet<KeyCode>,
+    /// The held keys that are not suppressed
+    held: Vec<KeyCode>,
+    /// The output keys, in the order in which they should be pressed
+    keys: Vec<KeyCode>,
+    /// The outputs of the `ordered` rules that apply
+    ordered: Vec<KeyCode>,
+    /// `keys` as a set
+    desired: HashSet<KeyCode>,
+    /// The output keys that `compute_changes` found should be released
+    to_release: Vec<KeyCode>,
+    /// The output keys that `compute_changes` found should be pressed
+    to_press: Vec<KeyCode>,
+}
+
+impl KeyTracker {
+    /// Forget which keys were consumed or suppressed, other than the
+    /// keys of `input_state` that were held when the inputs were grabbed
+    fn reset(&mut self, input_state: &[KeyCode]) {
+        self.consumed.clear();
+        self.held_at_grab.retain(|k| input_state.contains(k));
+        self.suppressed.clear();
+        self.suppressed.extend(self.held_at_grab.iter());
+    }
+
+    /// Ignore `keys`, which were held when the inputs were grabbed or
+    /// the kill switch was pressed, until they are released; the
+    /// output device isn't holding them, so it shouldn't see their
+    /// release either
+    fn ignore_until_released(&mut self, keys: &[KeyCode]) {
+        self.held_at_grab.extend(keys.iter());
+        self.suppressed.extend(keys.iter());
+    }
+
+    /// Called when the held key `code` is released
+    fn released(&mut self, code: &KeyCode) {
+        self.suppressed.remove(code);
+        self.held_at_grab.remove(code);
+    }
+
+    /// Compute the effective set of keys that are pressed while the
+    /// `input_state` keys are held, in the order in which they should
+    /// be pressed.
+    /// Scancode pseudo codes that no mapping consumed are
+    /// replaced by the key that they stand in for.
+    ///
+    /// A key that a `suppress` rule consumed is not passed through on
+    /// its own until it is released, so that releasing SHIFT before
+    /// BACKSPACE in a SHIFT+BACKSPACE rule doesn't type a BACKSPACE.
+    /// The keys of a chorded DualRole are treated in the same way even
+    /// if they are modifiers, so that releasing SPACE before RIGHTALT
+    /// from a RIGHTALT+SPACE chord doesn't press RIGHTALT.
+    fn compute_keys(
+        &mut self,
+        rules: &RuleSet,
+        input_state: &[KeyCode],
+        scanned_keys: &HashMap<KeyCode, KeyCode>,
+    ) -> &[KeyCode] {
+        loop {
+            self.held.clear();
+            self.held
+                .extend(input_state.iter().filter(|k| !self.suppressed.contains(k)));
+            let (keys, silenced) =
+                rules.compute_keys_silencing(&self.held, &mut self.keys, &mut self.ordered);
+            let mut revived = false;
+            for key in self
+                .held
+                .iter()
+                .filter(|k| keys.contains(k) && self.consumed.contains(k))
+            {
+                self.suppressed.insert(*key);
+                revived = true;
+            }
+            if !revived {
+                self.consumed.clear();
+                self.consumed.extend(
+                    self.held
+                        .iter()
+                        .filter(|k| !keys.contains(k) && silenced.contains(k)),
+                );
+                break;
+            }
+        }
+        if !scanned_keys.is_empty() {
+            for code in self.keys.iter_mut() {
+                if let Some((key, _)) = scanned_keys.iter().find(|(_, pseudo)| *pseudo == code) {
+                    *code = *key;
+                }
+            }
+        }
+        &self.keys
+    }
+
+    /// Compute the output keys as `compute_keys` does, and fill
+    /// `to_release` and `to_press` with the difference between them
+    /// and the `output_keys` that the output device is holding
+    fn compute_changes(
+        &mut self,
+        rules: &RuleSet,
+        input_state: &[KeyCode],
+        scanned_keys: &HashMap<KeyCode, KeyCode>,
+        output_keys: &HashSet<KeyCode>,
+    ) {
+        self.compute_keys(rules, input_state, scanned_keys);
+        self.desired.clear();
+        self.desired.extend(self.keys.iter());
+        self.to_release.clear();
+        self.to_release
+            .extend(output_keys.iter().filter(|k| !self.desired.contains(k)));
+        self.to_press.clear();
+        self.to_press
+            .extend(self.keys.iter().filter(|k| !output_keys.contains(k)));
+    }
+}
+
+fn enable_key_code(input: &Device, key: KeyCode) -> Result<()> {
+    input.enable(key).context(format!("enable key {:?}", key))?;
+    Ok(())
+}
+
+/// Enable the codes in `to` on `input` in place of those in `from`;
+/// these are the codes that the mappings emit but that the input
+/// doesn't support natively
+fn switch_added_codes(
+    input: &Device,
+    from: &HashSet<KeyCode>,
+    to: &HashSet<KeyCode>,
+) -> Result<()> {
+    for code in to.difference(from) {
+        enable_key_code(input, *code)?;
+    }
+    for code in from.difference(to) {
+        input.disable(*code).ok();
+    }
+    Ok(())
 }
 
-fn enable_key_code(input: &mut Device, key: KeyCode) -> Result<()> {
-    input
-        .enable(key.clone())
-        .context(format!("enable key {:?}", key))?;
+/// Enable the capabilities of `source` on `target`, so that the output
+/// device created from `target` can also emit the events of `source`
+fn merge_capabilities(target: &Device, source: &Device) -> Result<()> {
+    let codes = EventCode::EV_KEY(EV_KEY::KEY_RESERVED)
+        .iter()
+        .chain(EventCode::EV_REL(EV_REL::REL_X).iter())
+        .chain(EventCode::EV_ABS(EV_ABS::ABS_X).iter())
+        .chain(EventCode::EV_MSC(EV_MSC::MSC_SERIAL).iter());
+    for code in codes {
+        if !source.has(code) || target.has(code) {
+            continue;
+        }
+        match code {
+            EventCode::EV_ABS(_) => {
+                if let Some(info) = source.abs_info(&code) {
+                    target
+                        .enable_event_code(&code, Some(EnableCodeData::AbsInfo(info)))
+                        .context(format!("enable axis {:?}", code))?;
+                }
+            }
+            _ => enable_key_code(target, code)?,
+        }
+    }
     Ok(())
 }
 
+fn open_input<P: AsRef<Path>>(path: P) -> Result<Device> {
+    let path = path.as_ref();
+    // Opened non-blocking so that several devices can share the read loop
+    let f = std::fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+        .context(format!("opening {}", path.display()))?;
+    Device::new_from_file(f)
+        .with_context(|| format!("failed to create new Device from file {}", path.display()))
+}
+
+/// The keys that are currently held on `device`
+fn held_keys(device: &Device) -> Vec<KeyCode> {
+    (0..=EV_KEY::KEY_MAX as u32)
+        .map(|code| evdev_rs::util::int_to_event_code(EventType::EV_KEY as u32, code))
+        .filter(|code| device.has(*code) && device.event_value(code) == Some(1))
+        .collect()
+}
+
+/// Read the pending events of `devices`, so that libevdev's view of
+/// their keys is up to date, and return the keys that are held.
+/// libevdev reads the state of the keys (EVIOCGKEY) when a device is
+/// opened and keeps it up to date as events are read.
+fn current_held_keys(devices: &[&Device]) -> Result<Vec<KeyCode>> {
+    let mut held = vec![];
+    for device in devices {
+        loop {
+            match device.next_event(ReadFlag::NORMAL) {
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err).context("reading input events"),
+                _ => {}
+            }
+        }
+        for code in held_keys(device) {
+            // Siblings may report the same key
+            if !held.contains(&code) {
+                held.push(code);
+            }
+        }
+    }
+    Ok(held)
+}
+
+/// Wait until none of the keys of `devices` are held, so that they
+/// are grabbed between key presses rather than part way through one.
+/// Gives up after `timeout`, as a device may report a key that is
+/// always down.
+fn wait_for_keys_up(devices: &[&Device], timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let mut reported = false;
+    loop {
+        let held = current_held_keys(devices)?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if held.is_empty() || remaining.is_zero() {
+            return Ok(());
+        }
+        if !reported {
+            log::warn!(
+                "Waiting for {} to be released",
+                held.iter().map(code_name).collect::<Vec<_>>().join(", ")
+            );
+            reported = true;
+        }
+        let mut fds: Vec<libc::pollfd> = devices
+            .iter()
+            .map(|device| libc::pollfd {
+                fd: device.file().as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let timeout = remaining.as_millis() as libc::c_int + 1;
+        let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if res < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return Err(err).context("polling input devices");
+            }
+        }
+    }
+}
+
+/// Where the output frames are written.  This is the uinput device,
+/// other than in the tests.
+trait OutputDevice {
+    fn write_event(&self, event: &InputEvent) -> std::io::Result<()>;
+}
+
+impl OutputDevice for UInputDevice {
+    fn write_event(&self, event: &InputEvent) -> std::io::Result<()> {
+        UInputDevice::write_event(self, event)
+    }
+}
+
 impl InputMapper {
-    pub fn create_mapper<P: AsRef<Path>>(path: P, mappings: Vec<Mapping>) -> Result<Self> {
-        let path = path.as_ref();
-        let f = std::fs::File::open(path).context(format!("opening {}", path.display()))?;
-        let mut input_device = Device::new_from_file(f)
-            .with_context(|| format!("failed to create new Device from file {}", path.display()))?;
+    /// Open and grab the input devices at `paths`, the first of which
+    /// is the configured one and the others its siblings, and create
+    /// the output device.  Any keys that are held are given up to
+    /// `keys_up_timeout` to be released before the inputs are grabbed.
+    pub fn create_mapper<P: AsRef<Path>>(
+        paths: &[P],
+        config: MappingConfig,
+        keys_up_timeout: Duration,
+    ) -> Result<Self> {
+        let path = match paths.first() {
+            Some(path) => path.as_ref(),
+            None => bail!("no input devices to remap"),
+        };
+        let mut input_device = open_input(path)?;
+        let mut siblings = vec![];
+        for sibling_path in &paths[1..] {
+            let sibling = open_input(sibling_path)?;
+            merge_capabilities(&input_device, &sibling).context(format!(
+                "merging capabilities of {}",
+                sibling_path.as_ref().display()
+            ))?;
+            siblings.push(sibling);
+        }
 
         input_device.set_name(&format!("evremap Virtual input for {}", path.display()));
-        let mut mapped_types = HashSet::new();
+        let (_, output_codes) = mapping_requirements(&config.mappings);
         // Ensure that any remapped keys are supported by the generated output device
-        for map in &mappings {
-            match map {
-                Mapping::DualRole {input, tap, hold, .. } => {
-                    mapped_types.insert(to_event_type(input));
-                    for t in tap {
-                        enable_key_code(&mut input_device, t.clone())?;
-                    }
-                    for h in hold {
-                        enable_key_code(&mut input_device, h.clone())?;
-                    }
-                }
-                Mapping::Remap { input, output, .. } => {
-                    for i in input {
-                        mapped_types.insert(to_event_type(&i.code));
-                    }
-                    for o in output {
-                        enable_key_code(&mut input_device, o.code.clone())?;
-                    }
-                }
+        let mut added_codes = HashSet::new();
+        for code in output_codes {
+            if !input_device.has(code) {
+                enable_key_code(&input_device, code)?;
+                added_codes.insert(code);
             }
         }
 
         let output = UInputDevice::create_from_device(&input_device)
             .context(format!("creating UInputDevice from {}", path.display()))?;
 
+        let devices: Vec<&Device> = std::iter::once(&input_device).chain(&siblings).collect();
+        wait_for_keys_up(&devices, keys_up_timeout)?;
+
+        // From here on, the held keys are released before stopping
+        let shutdown_fd = signal_fd(&[libc::SIGINT, libc::SIGTERM, libc::SIGQUIT])
+            .context("handling SIGINT, SIGTERM and SIGQUIT")?;
+
         input_device
             .grab(GrabMode::Grab)
             .context(format!("grabbing exclusive access on {}", path.display()))?;
+        for (sibling, sibling_path) in siblings.iter_mut().zip(&paths[1..]) {
+            sibling.grab(GrabMode::Grab).context(format!(
+                "grabbing exclusive access on {}",
+                sibling_path.as_ref().display()
+            ))?;
+        }
+
+        // A key may have been pressed since the last check, so the
+        // held keys are read again now that nothing else sees them
+        let devices: Vec<&Device> = std::iter::once(&input_device).chain(&siblings).collect();
+        let held = current_held_keys(&devices)?;
+        if !held.is_empty() {
+            log::warn!(
+                "Grabbing the inputs while {} {} still held",
+                held.iter().map(code_name).collect::<Vec<_>>().join(", "),
+                if held.len() == 1 { "is" } else { "are" }
+            );
+        }
+
+        let mut inputs = vec![input_device];
+        inputs.append(&mut siblings);
+        Self::new(
+            inputs,
+            paths.iter().map(|p| p.as_ref().to_path_buf()).collect(),
+            Box::new(output),
+            config,
+            added_codes,
+            held,
+            shutdown_fd,
+        )
+    }
+
+    /// Set up the mapper for the grabbed `inputs` and the `output`
+    /// device, for which `added_codes` were enabled.  The `held` keys
+    /// were held when the inputs were grabbed; they are treated as
+    /// held but suppressed, so that neither they nor their release
+    /// are passed through.
+    fn new(
+        inputs: Vec<Device>,
+        input_paths: Vec<PathBuf>,
+        output: Box<dyn OutputDevice>,
+        config: MappingConfig,
+        added_codes: HashSet<KeyCode>,
+        held: Vec<KeyCode>,
+        shutdown_fd: OwnedFd,
+    ) -> Result<Self> {
+        let (mapped_types, _) = mapping_requirements(&config.mappings);
+        let scan_codes = mapping_scan_codes(&config.mappings);
+        let rules = Rc::new(RuleSet::new(config.mappings, config.modifiers)?);
+        let mut tracker = KeyTracker::default();
+        tracker.ignore_until_released(&held);
+        let frames = vec![vec![]; input_paths.len()];
+        if config.kill_switch == Some(KillSwitch::fallback()) {
+            log::info!(
+                "Holding both SHIFT keys and pressing ESC passes events through unchanged \
+                 until it is pressed again; configure [kill_switch] to change or disable this"
+            );
+        }
 
         Ok(Self {
-            input: input_device,
+            inputs,
+            input_paths,
             output,
-            input_state: HashMap::new(),
+            input_state: held.clone(),
             output_keys: HashSet::new(),
+            frames,
+            frame_input: 0,
+            press_order: vec![],
+            tracker,
             tapping: None,
-            mappings,
+            scan_codes,
+            pending_scan: None,
+            scanned_keys: HashMap::new(),
+            rules,
+            origins: config.origins,
             mapped_types,
+            added_codes,
+            config_watcher: None,
+            shutdown_fd,
+            watchdog: config.watchdog,
+            kill_switch: config.kill_switch,
+            swallowed_key: None,
+            physical_keys: held.into_iter().collect(),
+            passthrough: false,
+            last_event: Instant::now(),
+            runtime: config.runtime,
+            latency: LatencyLog::new(config.runtime.latency_log_interval),
+            privileges: config.privileges,
+            dropped_privileges: false,
+            sandbox: config.sandbox,
+            config_files: config.files,
+            fragment_dir: config.fragment_dir,
         })
     }
 
-    pub fn run_mapper(&mut self) -> Result<()> {
-        log::info!("Going into read loop");
-        loop {
-            let (status, event) = self
-                .input
-                .next_event(ReadFlag::NORMAL | ReadFlag::BLOCKING)?;
-            match status {
-                evdev_rs::ReadStatus::Success => {
-                    if self.mapped_types.contains(&to_event_type(&event.event_code)) {
-                        log::trace!("IN {:?}", event);
-                        self.update_with_event(&event, event.event_code.clone())?;
-                    } else {
-                        log::trace!("PASSTHRU {:?}", event);
-                        self.output.write_event(&event)?;
-                    }
-                }
-                evdev_rs::ReadStatus::Sync => bail!("ReadStatus::Sync!"),
+    /// Reload the mappings whenever `watcher` says that it is time
+    pub fn watch_config(&mut self, watcher: ConfigWatcher) {
+        self.config_watcher = Some(watcher);
+    }
+
+    /// Switch over to a new set of mappings.
+    /// The output keys held under the old mappings are released and
+    /// then the current input state is re-applied using the new ones.
+    /// The output device is only recreated if the new mappings need
+    /// a different set of codes to be enabled, which isn't possible
+    /// once privileges have been dropped.  The new device is created
+    /// first, so that nothing changes if that fails.
+    pub fn replace_mappings(&mut self, config: MappingConfig) -> Result<()> {
+        let (mapped_types, output_codes) = mapping_requirements(&config.mappings);
+        let scan_codes = mapping_scan_codes(&config.mappings);
+        let rules = Rc::new(RuleSet::new(config.mappings, config.modifiers)?);
+        let input = &self.inputs[0];
+        let added_codes: HashSet<KeyCode> = output_codes
+            .into_iter()
+            .filter(|code| self.added_codes.contains(code) || !input.has(*code))
+            .collect();
+
+        let recreate = added_codes != self.added_codes;
+        if recreate && self.dropped_privileges {
+            bail!(
+                "the new mappings need the output device to be recreated with a different \
+                 set of codes, which can't be done after dropping privileges; restart \
+                 evremap to apply them"
+            );
+        }
+        let output = if recreate {
+            log::info!("Recreating the output device for the new set of codes");
+            let created =
+                switch_added_codes(input, &self.added_codes, &added_codes).and_then(|_| {
+                    UInputDevice::create_from_device(input).context("recreating UInputDevice")
+                });
+            if created.is_err() {
+                switch_added_codes(input, &added_codes, &self.added_codes).ok();
             }
+            Some(created?)
+        } else {
+            None
+        };
+
+        let time = now();
+        let mut held: Vec<KeyCode> = self.output_keys.iter().cloned().collect();
+        self.sort_for_release(&mut held);
+        // Anything buffered belongs to the old device
+        let released = self
+            .flush_all_frames(&time)
+            .and_then(|_| self.emit_keys(&held, &time, KeyEventType::Release))
+            .and_then(|_| self.flush_frame(&time));
+        if released.is_err() && output.is_some() {
+            switch_added_codes(&self.inputs[0], &added_codes, &self.added_codes).ok();
         }
-    }
+        released?;
 
-    /// Compute the effective set of keys that are pressed
-    fn compute_keys(&self) -> HashSet<KeyCode> {
-        // Start with the input keys
-        let mut keys: HashSet<KeyCode> = self.input_state.keys().cloned().collect();
+        if let Some(output) = output {
+            self.output = Box::new(output);
+            self.added_codes = added_codes;
+        }
 
-        // First phase is to apply any DualRole mappings as they are likely to
-        // be used to produce modifiers when held.
-        for map in &self.mappings {
-            if let Mapping::DualRole { input, hold, .. } = map {
-                if keys.contains(input) {
-                    keys.remove(input);
-                    for h in hold {
-                        keys.insert(h.clone());
-                    }
-                }
+        // Keys that were consumed, suppressed or recognized by their
+        // scancode under the old mappings start afresh with the new ones
+        for (key, pseudo) in self.scanned_keys.drain() {
+            for held in self.input_state.iter_mut().filter(|k| **k == pseudo) {
+                *held = key;
             }
         }
+        self.tracker.reset(&self.input_state);
+        self.pending_scan = None;
+        self.scan_codes = scan_codes;
+        self.rules = rules;
+        self.origins = config.origins;
+        self.watchdog = config.watchdog;
+        self.kill_switch = config.kill_switch;
+        if config.runtime != self.runtime {
+            log::warn!("Changes to the [runtime] section take effect when evremap is restarted");
+        }
+        if config.privileges != self.privileges {
+            log::warn!("Changes to the [privileges] section take effect when evremap is restarted");
+        }
+        if config.sandbox != self.sandbox {
+            log::warn!("Changes to the [sandbox] section take effect when evremap is restarted");
+        }
+        self.mapped_types = mapped_types;
+        self.cancel_pending_tap();
+        if self.passthrough {
+            // The new mappings apply once the kill switch is pressed again
+            return Ok(());
+        }
+        self.compute_and_apply_keys(&time)?;
+        self.flush_frame(&time)
+    }
 
-        let mut keys_minus_remapped = keys.clone();
+    fn reload_config(&mut self) -> Result<()> {
+        let loaded = match self.config_watcher.as_mut() {
+            Some(watcher) => watcher.load(),
+            None => return Ok(()),
+        };
+        let landlock = self.sandbox.as_ref().is_some_and(|s| s.landlock);
+        let config = match loaded {
+            Err(err) if landlock && sandbox::denied(&err) => {
+                log::error!(
+                    "{:#}; the sandbox only allows the configuration files that evremap \
+                     started with, as they were then, and new fragments; restart evremap \
+                     to apply the new configuration",
+                    err
+                );
+                return Ok(());
+            }
+            Err(err) => {
+                log::error!("{:#}; keeping the current mappings", err);
+                return Ok(());
+            }
+            loaded => loaded?,
+        };
+        if let Err(err) = self.replace_mappings(config) {
+            log::error!(
+                "Failed to apply the new configuration: {:#}; keeping the current mappings",
+                err
+            );
+        } else {
+            log::info!("Reloaded the configuration");
+        }
+        Ok(())
+    }
 
-        // Second pass to apply Remap items
-        for map in &self.mappings {
-            if let Mapping::Remap { input, output } = map {
-                if keys_minus_remapped.is_superset(&input.into_iter().map(|k|k.code).collect()) {
-                    for i in input {
-                        keys.remove(&i.code);
-                        if let EventCode::EV_KEY(k) = i.code {
-                            if !is_modifier(&k) {
-                                keys_minus_remapped.remove(&i.code);
-                            }
-                        }
+    pub fn run_mapper(&mut self) -> Result<StopReason> {
+        // This needs the privileges that are about to be dropped
+        configure_thread(&self.runtime)?;
+        if let Some(privileges) = &self.privileges {
+            drop_privileges(privileges)?;
+            self.dropped_privileges = true;
+        }
+        if let Some(config) = &self.sandbox {
+            sandbox::restrict(config, &self.config_files, &self.fragment_dir)?;
+        }
+        log::info!("Going into read loop");
+        let watcher_fds = match &self.config_watcher {
+            Some(watcher) => watcher.fds(),
+            None => vec![],
+        };
+        let shutdown = self.inputs.len();
+        let mut fds: Vec<libc::pollfd> = self
+            .inputs
+            .iter()
+            .map(|input| input.file().as_raw_fd())
+            .chain(std::iter::once(self.shutdown_fd.as_raw_fd()))
+            .chain(watcher_fds)
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        loop {
+            let timeout = self.watchdog_timeout();
+            let res = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+            if res < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err).context("polling input devices");
+            }
+            if res == 0 {
+                if self.run_watchdog()? {
+                    log::warn!("Kill switch pressed; stopping");
+                    self.release_all();
+                    return Ok(StopReason::KillSwitch);
+                }
+                continue;
+            }
+            let mut reload = false;
+            for (idx, pollfd) in fds.iter().enumerate() {
+                let revents = pollfd.revents;
+                if idx == shutdown {
+                    if revents & libc::POLLIN != 0 {
+                        log::info!("Received {}; stopping", self.read_shutdown_signal()?);
+                        self.release_all();
+                        return Ok(StopReason::Signal);
                     }
-                    for o in output {
-                        keys.insert(o.code.clone());
-                        // Outputs that apply are not visible as
-                        // inputs for later remap rules
-                        if let EventCode::EV_KEY(k) = o.code {
-                            if !is_modifier(&k) {
-                                keys_minus_remapped.remove(&o.code);
-                            }
+                    continue;
+                }
+                if idx > shutdown {
+                    if revents & libc::POLLIN != 0 {
+                        if let Some(watcher) = self.config_watcher.as_mut() {
+                            reload |= watcher.consume(pollfd.fd)?;
                         }
                     }
+                    continue;
                 }
+                if revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+                    bail!(
+                        "input device {} is no longer available",
+                        self.input_paths[idx].display()
+                    );
+                }
+                if revents & libc::POLLIN != 0 && self.drain_input(idx)? {
+                    log::warn!("Kill switch pressed; stopping");
+                    self.release_all();
+                    return Ok(StopReason::KillSwitch);
+                }
+            }
+            if reload {
+                self.reload_config()?;
             }
         }
+    }
 
-        keys
+    /// Read the signal that is pending on `shutdown_fd`, returning its name
+    fn read_shutdown_signal(&self) -> Result<&'static str> {
+        let mut info: libc::signalfd_siginfo = unsafe { std::mem::zeroed() };
+        let size = std::mem::size_of::<libc::signalfd_siginfo>();
+        let len = unsafe {
+            libc::read(
+                self.shutdown_fd.as_raw_fd(),
+                &mut info as *mut _ as *mut libc::c_void,
+                size,
+            )
+        };
+        if len != size as isize {
+            return Err(std::io::Error::last_os_error()).context("reading the shutdown signal");
+        }
+        Ok(match info.ssi_signo as libc::c_int {
+            libc::SIGINT => "SIGINT",
+            libc::SIGTERM => "SIGTERM",
+            _ => "SIGQUIT",
+        })
     }
 
-    /// Compute the difference between our desired set of keys
-    /// and the set of keys that are currently pressed in the
-    /// output device.
-    /// Release any keys that should not be pressed, and then
-    /// press any keys that should be pressed.
-    ///
-    /// When releasing, release modifiers last so that mappings
-    /// that produce eg: CTRL-C don't emit a random C character
-    /// when released.
-    ///
-    /// Similarly, when pressing, emit modifiers first so that
-    /// we don't emit C and then CTRL for such a mapping.
-    fn compute_and_apply_keys(&mut self, time: &TimeVal) -> Result<()> {
-        let desired_keys = self.compute_keys();
-        let mut to_release: Vec<KeyCode> = self
+    /// Release every key that the output device is holding and give
+    /// up exclusive access to the inputs, so that nothing is left held
+    /// when the remapper stops.  This is also done when the mapper is
+    /// dropped, which covers errors and panics.
+    fn release_all(&mut self) {
+        let time = now();
+        if let Err(err) = self
+            .flush_all_frames(&time)
+            .and_then(|_| self.release_output())
+            .and_then(|_| self.flush_frame(&time))
+        {
+            log::error!("Failed to release the held keys: {:#}", err);
+        }
+        for input in &mut self.inputs {
+            if let Err(err) = input.grab(GrabMode::Ungrab) {
+                log::error!("Failed to ungrab an input device: {}", err);
+            }
+        }
+    }
+
+    /// Release every key that the output device is holding
+    fn release_output(&mut self) -> Result<()> {
+        let mut keys: Vec<KeyCode> = self.output_keys.iter().cloned().collect();
+        self.sort_for_release(&mut keys);
+        self.press_order.clear();
+        self.cancel_pending_tap();
+        if keys.is_empty() {
+            return Ok(());
+        }
+        log::info!(
+            "Releasing {}",
+            keys.iter().map(code_name).collect::<Vec<_>>().join(", ")
+        );
+        self.emit_keys(&keys, &now(), KeyEventType::Release)
+    }
+
+    /// How long `run_mapper` may wait for input before the watchdog
+    /// needs to run, in milliseconds, or -1 if it doesn't need to
+    fn watchdog_timeout(&self) -> libc::c_int {
+        if self.watchdog.idle_timeout == 0 || self.output_keys.is_empty() || self.passthrough {
+            return -1;
+        }
+        let deadline = self.last_event + Duration::from_secs(self.watchdog.idle_timeout);
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        // Round up so that the deadline has passed when poll returns
+        (remaining.as_millis() + 1).min(libc::c_int::MAX as u128) as libc::c_int
+    }
+
+    /// Called when keys have been held on the output device without
+    /// any input for `idle_timeout` seconds.  Any pending events are
+    /// processed first, which resynchronizes an input that dropped
+    /// events, and then any output keys that the held input keys don't
+    /// explain are released.  Returns true if the kill switch asked the
+    /// remapper to stop.
+    fn run_watchdog(&mut self) -> Result<bool> {
+        for idx in 0..self.inputs.len() {
+            if self.drain_input(idx)? {
+                return Ok(true);
+            }
+        }
+        self.last_event = Instant::now();
+        let time = now();
+        self.release_stuck_keys(&time)?;
+        self.flush_frame(&time)?;
+        Ok(false)
+    }
+
+    /// Release the output keys that the held input keys don't explain,
+    /// logging the rule that can produce each of them
+    fn release_stuck_keys(&mut self, time: &TimeVal) -> Result<()> {
+        let desired = self
+            .tracker
+            .compute_keys(&self.rules, &self.input_state, &self.scanned_keys);
+        let mut stuck: Vec<KeyCode> = self
             .output_keys
-            .difference(&desired_keys)
+            .iter()
+            .filter(|k| !desired.contains(k))
             .cloned()
             .collect();
+        stuck.sort_by_key(code_name);
+        for key in &stuck {
+            log::warn!("{}", self.stuck_key_warning(key));
+        }
+        self.sort_for_release(&mut stuck);
+        self.emit_keys(&stuck, time, KeyEventType::Release)
+    }
 
-        let mut to_press: Vec<KeyCode> = desired_keys
-            .difference(&self.output_keys)
-            .cloned()
-            .collect();
+    fn stuck_key_warning(&self, key: &KeyCode) -> String {
+        match self.rule_for(key) {
+            Some(origin) => format!(
+                "{} is stuck on the output device; releasing it. It is an output of {}",
+                code_name(key),
+                origin
+            ),
+            None => format!(
+                "{} is stuck on the output device; releasing it. \
+                 It is not the output of any rule",
+                code_name(key)
+            ),
+        }
+    }
+
+    /// The origin of the first rule that can output `key`
+    fn rule_for(&self, key: &KeyCode) -> Option<&RuleOrigin> {
+        let idx = self.rules.mappings.iter().position(|map| match map {
+            Mapping::DualRole { hold, tap, .. } => hold.contains(key) || tap.contains(key),
+            Mapping::Remap { output, .. } => output.iter().any(|o| o.code == *key),
+        })?;
+        self.origins.get(idx)
+    }
 
-        if !to_release.is_empty() {
-            to_release.sort_by(modifiers_last);
-            self.emit_keys(&to_release, time, KeyEventType::Release)?;
+    /// Process all of the events that are currently pending for
+    /// the input at `idx`.  Returns true if the kill switch asked the
+    /// remapper to stop.
+    fn drain_input(&mut self, idx: usize) -> Result<bool> {
+        self.last_event = Instant::now();
+        loop {
+            let (status, event) = match self.inputs[idx].next_event(ReadFlag::NORMAL) {
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => return Ok(false),
+                other => other?,
+            };
+            match status {
+                evdev_rs::ReadStatus::Success => {
+                    if self.process_event(idx, event)? {
+                        return Ok(true);
+                    }
+                }
+                evdev_rs::ReadStatus::Sync => {
+                    log::warn!(
+                        "input device {} dropped events; resynchronizing",
+                        self.input_paths[idx].display()
+                    );
+                    self.resync_input(idx)?;
+                    if !self.passthrough {
+                        self.compute_and_apply_keys(&now())?;
+                    }
+                    self.flush_frame(&now())?;
+                }
+            }
         }
-        if !to_press.is_empty() {
-            to_press.sort_by(modifiers_first);
-            self.emit_keys(&to_press, time, KeyEventType::Press)?;
+    }
+
+    /// Process an `event` that was read from the input at `idx`.
+    /// Returns true if the kill switch asked the remapper to stop.
+    fn process_event(&mut self, idx: usize, event: InputEvent) -> Result<bool> {
+        self.frame_input = idx;
+        if event.event_code == EventCode::EV_SYN(EV_SYN::SYN_REPORT) {
+            // Everything that the input's frame turned into goes out
+            // as a single frame
+            self.pending_scan = None;
+            let written = !self.frames[idx].is_empty();
+            self.flush_frame(&event.time)?;
+            if written {
+                self.latency.record(&event.time);
+            }
+            return Ok(false);
         }
-        Ok(())
+        // The press that completes the kill switch is swallowed,
+        // whatever mode the remapper is in
+        match self.track_physical_key(&event) {
+            Some(KillSwitchAction::Passthrough) => {
+                self.swallowed_key = Some(event.event_code);
+                self.toggle_passthrough()?;
+                return Ok(false);
+            }
+            Some(KillSwitchAction::Exit) => return Ok(true),
+            None => {}
+        }
+        if self.swallowed_key == Some(event.event_code) {
+            if event.value == KeyEventType::Release.value() {
+                self.swallowed_key = None;
+            }
+            return Ok(false);
+        }
+        if self.passthrough {
+            // Keys that were held when passthrough was switched on were
+            // released on the output, so their repeats and release are
+            // left out
+            if is_key_code(&event.event_code)
+                && event.value != KeyEventType::Press.value()
+                && !self.output_keys.contains(&event.event_code)
+            {
+                return Ok(false);
+            }
+            log::trace!("PASSTHRU {:?}", event);
+            self.write_event(&event)?;
+            return Ok(false);
+        }
+        let code = self.translate_scan_code(&event);
+        if self
+            .mapped_types
+            .contains(&to_event_type(&event.event_code))
+        {
+            log::trace!("IN {:?}", event);
+            self.update_with_event(&event, code)?;
+        } else {
+            log::trace!("PASSTHRU {:?}", event);
+            self.frames[idx].push(event);
+        }
+        Ok(false)
     }
 
-    fn lookup_dual_role_mapping(&self, code: KeyCode) -> Option<Mapping> {
-        for map in &self.mappings {
-            if let Mapping::DualRole { input, .. } = map {
-                if *input == code {
-                    // A DualRole mapping has the highest precedence
-                    // so we've found our match
-                    return Some(map.clone());
-                }
+    /// Keep `physical_keys` up to date with `event`, returning the
+    /// action of the kill switch if this press completes it
+    fn track_physical_key(&mut self, event: &InputEvent) -> Option<KillSwitchAction> {
+        if !matches!(event.event_code, EventCode::EV_KEY(_)) {
+            return None;
+        }
+        match KeyEventType::from_value(event.value) {
+            KeyEventType::Press => {
+                self.physical_keys.insert(event.event_code);
             }
+            KeyEventType::Release => {
+                self.physical_keys.remove(&event.event_code);
+                return None;
+            }
+            _ => return None,
         }
-        None
+        let kill_switch = self.kill_switch.as_ref()?;
+        kill_switch
+            .pressed_by(&event.event_code, &self.physical_keys)
+            .then_some(kill_switch.action)
     }
 
-    fn lookup_mapping(&self, code: KeyCode, value: i32) -> Option<Mapping> {
-        let mut candidates = vec![];
+    /// Switch between applying the mappings and passing every event
+    /// through unchanged.  Whatever the output device is holding is
+    /// released first, so that nothing from one mode is left held in
+    /// the other, and the keys that are still held are ignored until
+    /// they are released.
+    fn toggle_passthrough(&mut self) -> Result<()> {
+        self.release_output()?;
+        self.pending_scan = None;
+        self.scanned_keys.clear();
+        // The input state isn't kept up to date while passing events
+        // through, so it is taken from the physical keys
+        self.input_state.clear();
+        self.input_state.extend(
+            self.physical_keys
+                .iter()
+                .filter(|k| Some(**k) != self.swallowed_key),
+        );
+        self.input_state.sort_by_key(code_name);
+        self.tracker.reset(&self.input_state);
+        self.tracker.ignore_until_released(&self.input_state);
+        self.passthrough = !self.passthrough;
+        if self.passthrough {
+            log::warn!(
+                "Kill switch pressed; passing events through unchanged until it is pressed again"
+            );
+        } else {
+            log::warn!("Kill switch pressed; applying the mappings again");
+        }
+        Ok(())
+    }
 
-        for map in &self.mappings {
-            match map {
-                Mapping::DualRole { input, .. } => {
-                    if *input == code {
-                        // A DualRole mapping has the highest precedence
-                        // so we've found our match
-                        return Some(map.clone());
+    /// Bring the held keys up to date after the kernel dropped events
+    /// from the input at `idx`.  libevdev delivers the difference
+    /// between the state that we last saw and the current state of the
+    /// device; the caller then recomputes the outputs from the new
+    /// state, releasing or pressing whatever changed.
+    fn resync_input(&mut self, idx: usize) -> Result<()> {
+        self.frame_input = idx;
+        loop {
+            let (_, event) = match self.inputs[idx].next_event(ReadFlag::SYNC) {
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                other => other?,
+            };
+            // The caller ends the frame once the state is up to date
+            if event.event_code == EventCode::EV_SYN(EV_SYN::SYN_REPORT) {
+                self.pending_scan = None;
+                continue;
+            }
+            // A kill switch that was completed during the gap isn't
+            // acted upon; it has to be pressed again
+            self.track_physical_key(&event);
+            if self.passthrough {
+                log::trace!("SYNC PASSTHRU {:?}", event);
+                self.write_event(&event)?;
+                continue;
+            }
+            let code = self.translate_scan_code(&event);
+            if !self
+                .mapped_types
+                .contains(&to_event_type(&event.event_code))
+            {
+                log::trace!("SYNC PASSTHRU {:?}", event);
+                self.frames[idx].push(event);
+                continue;
+            }
+            log::trace!("SYNC {:?}", event);
+            if event.event_type() == Some(EventType::EV_KEY) {
+                match KeyEventType::from_value(event.value) {
+                    KeyEventType::Press if !self.input_state.contains(&code) => {
+                        self.input_state.push(code);
                     }
+                    KeyEventType::Release => {
+                        self.input_state.retain(|k| *k != code);
+                        self.tracker.released(&code);
+                    }
+                    _ => {}
                 }
-                Mapping::Remap { input, .. } => {
-                    // Look for a mapping that includes the current key.
-                    // If part of a chord, all of its component keys must
-                    // also be pressed.
-                    let mut code_matched = false;
-                    let mut all_matched = true;
-                    for i in input {
-                        if *i == code {
-                            code_matched = match i.code {
-                                EventCode::EV_KEY(_) => true,
-                                _ => i.scale == 0 || i.scale.is_negative() == value.is_negative()
-                            }
-                        } else if !self.input_state.contains_key(&i.code) {
-                            all_matched = false;
-                            break;
-                        }
+            }
+        }
+        // The timing of a tap can't be trusted across the gap
+        self.cancel_pending_tap();
+        Ok(())
+    }
+
+    /// Track the `MSC_SCAN` events for the scancodes that the mappings
+    /// refer to, and return the code that the mappings should see for
+    /// `event`.  That is the pseudo code for the scancode in the case
+    /// of the key event that follows it, along with any repeats and the
+    /// release of that key, and the code of the event otherwise.
+    fn translate_scan_code(&mut self, event: &InputEvent) -> KeyCode {
+        match event.event_code {
+            EventCode::EV_MSC(EV_MSC::MSC_SCAN) => {
+                let scan = event.value as u32;
+                self.pending_scan = self.scan_codes.contains(&scan).then_some(scan);
+            }
+            EventCode::EV_SYN(_) => {
+                self.pending_scan = None;
+            }
+            key if event.event_type() == Some(EventType::EV_KEY) => {
+                if let Some(pseudo) = self.scanned_keys.get(&key).cloned() {
+                    if event.value == KeyEventType::Release.value() {
+                        self.scanned_keys.remove(&key);
                     }
-                    if code_matched && all_matched {
-                        candidates.push(map);
+                    return pseudo;
+                }
+                if let Some(scan) = self.pending_scan.take() {
+                    if event.value == KeyEventType::Press.value() {
+                        let pseudo = scan_code(scan);
+                        self.scanned_keys.insert(key, pseudo);
+                        return pseudo;
                     }
                 }
             }
+            _ => {}
         }
+        event.event_code
+    }
 
-        // Any matches must be Remap entries.  We want the one
-        // with the most active keys
-        candidates.sort_by(|a, b| match (a, b) {
-            (Mapping::Remap { input: input_a, .. }, Mapping::Remap { input: input_b, .. }) => {
-                input_a.len().cmp(&input_b.len()).reverse()
-            }
-            _ => unreachable!(),
+    /// Compute the difference between our desired set of keys
+    /// and the set of keys that are currently pressed in the
+    /// output device.
+    /// Release any keys that should not be pressed, and then
+    /// press any keys that should be pressed.
+    ///
+    /// When releasing, release modifiers last so that mappings
+    /// that produce eg: CTRL-C don't emit a random C character
+    /// when released.
+    ///
+    /// Similarly, when pressing, emit modifiers first so that
+    /// we don't emit C and then CTRL for such a mapping.
+    /// The outputs of `ordered` rules are instead pressed in the
+    /// order that they are written, and released in reverse.
+    fn compute_and_apply_keys(&mut self, time: &TimeVal) -> Result<()> {
+        self.tracker.compute_changes(
+            &self.rules,
+            &self.input_state,
+            &self.scanned_keys,
+            &self.output_keys,
+        );
+        // Taken for the duration so that the keys can be emitted;
+        // they are handed back to be reused by the next event
+        let mut to_release = std::mem::take(&mut self.tracker.to_release);
+        let to_press = std::mem::take(&mut self.tracker.to_press);
+        self.sort_for_release(&mut to_release);
+        let emitted = self
+            .emit_keys(&to_release, time, KeyEventType::Release)
+            .and_then(|_| self.emit_keys(&to_press, time, KeyEventType::Press));
+        self.tracker.to_release = to_release;
+        self.tracker.to_press = to_press;
+        emitted?;
+        self.press_order.clear();
+        self.press_order.extend_from_slice(&self.tracker.keys);
+        Ok(())
+    }
+
+    /// Sort `keys` into the reverse of the order in which they were
+    /// pressed.  Keys that the mappings didn't ask for go first.
+    fn sort_for_release(&self, keys: &mut [KeyCode]) {
+        keys.sort_by_key(|key| {
+            let pressed_at = self.press_order.iter().position(|k| k == key);
+            Reverse((
+                pressed_at.unwrap_or(usize::MAX),
+                press_rank(key, &self.rules.modifiers, &[]),
+            ))
         });
+    }
 
-        candidates.get(0).map(|&m| m.clone())
+    /// The index of the mapping that applies to an event for `code`
+    fn lookup_mapping(&self, code: KeyCode, value: i32) -> Option<usize> {
+        self.rules.lookup_mapping(&self.input_state, code, value)
     }
 
     pub fn update_with_event(&mut self, event: &InputEvent, code: KeyCode) -> Result<()> {
+        let rules = Rc::clone(&self.rules);
         match event.event_type().ok_or("Unknown event type").unwrap() {
             EventType::EV_KEY => {
                 let event_type = KeyEventType::from_value(event.value);
                 match event_type {
                     KeyEventType::Release => {
-                        let pressed_at = match self.input_state.remove(&code) {
+                        // Nothing should be left held once every input
+                        // key is up, so whatever the last held key
+                        // doesn't explain is stuck
+                        if self.input_state == [code] {
+                            self.release_stuck_keys(&event.time)?;
+                        }
+                        match self.input_state.iter().position(|k| *k == code) {
+                            Some(pos) => {
+                                self.input_state.remove(pos);
+                            }
                             None => {
-                                self.write_event_and_sync(event)?;
+                                self.write_event(event)?;
                                 return Ok(());
                             }
-                            Some(p) => p,
-                        };
+                        }
+                        self.tracker.released(&code);
 
                         self.compute_and_apply_keys(&event.time)?;
 
-                        if let Some(Mapping::DualRole { tap, .. }) =
-                            self.lookup_dual_role_mapping(code.clone())
+                        // If released quickly enough, becomes a tap press.
+                        // Releasing any key of a chord ends the tap.
+                        if self
+                            .tapping
+                            .as_ref()
+                            .is_some_and(|t| t.input.contains(&code))
                         {
-                            // If released quickly enough, becomes a tap press.
                             if let Some(tapping) = self.tapping.take() {
-                                if tapping == code
-                                    && timeval_diff(&event.time, &pressed_at) <= Duration::from_millis(200)
+                                if timeval_diff(&event.time, &tapping.since)
+                                    <= Duration::from_millis(200)
                                 {
-                                    self.emit_keys(&tap, &event.time, KeyEventType::Press)?;
-                                    self.emit_keys(&tap, &event.time, KeyEventType::Release)?;
+                                    self.emit_keys(&tapping.tap, &event.time, KeyEventType::Press)?;
+                                    // A press and release in the same frame
+                                    // may be collapsed into nothing, so the
+                                    // tap gets a frame of its own
+                                    self.flush_frame(&event.time)?;
+                                    self.emit_keys(
+                                        &tapping.tap,
+                                        &event.time,
+                                        KeyEventType::Release,
+                                    )?;
                                 }
                             }
                         }
                     }
                     KeyEventType::Press => {
-                        self.input_state.insert(code.clone(), event.time.clone());
+                        self.input_state.retain(|k| *k != code);
+                        self.input_state.push(code);
 
-                        match self.lookup_mapping(code.clone(), KeyEventType::Press.value()) {
-                            Some(_) => {
+                        let mapping = self.lookup_mapping(code, KeyEventType::Press.value());
+                        match mapping.map(|idx| &rules.mappings[idx]) {
+                            Some(Mapping::DualRole { input, tap, .. }) => {
                                 self.compute_and_apply_keys(&event.time)?;
-                                self.tapping.replace(code);
+                                self.tapping.replace(PendingTap {
+                                    input: input.clone(),
+                                    tap: tap.clone(),
+                                    since: event.time,
+                                });
                             }
-                            None => {
-                                // Just pass it through
+                            _ => {
+                                // Remapped or passed through; either
+                                // way, any pending tap is cancelled
                                 self.cancel_pending_tap();
                                 self.compute_and_apply_keys(&event.time)?;
                             }
                         }
                     }
+                    KeyEventType::Repeat if self.tracker.suppressed.contains(&code) => {}
                     KeyEventType::Repeat => {
-                        match self.lookup_mapping(code.clone(), KeyEventType::Repeat.value()) {
+                        let mapping = self.lookup_mapping(code, KeyEventType::Repeat.value());
+                        match mapping.map(|idx| &rules.mappings[idx]) {
                             Some(Mapping::DualRole { hold, .. }) => {
-                                self.emit_keys(&hold, &event.time, KeyEventType::Repeat)?;
+                                self.emit_keys(hold, &event.time, KeyEventType::Repeat)?;
                             }
                             Some(Mapping::Remap { output, .. }) => {
-                                let output: Vec<KeyCode> = output.into_iter().map(|k|k.code).collect();
-                                self.emit_keys(&output, &event.time, KeyEventType::Repeat)?;
+                                for k in output.iter().filter(|k| !is_virtual_code(&k.code)) {
+                                    self.write_event(&make_event(
+                                        k.code,
+                                        &event.time,
+                                        KeyEventType::Repeat,
+                                    ))?;
+                                }
                             }
                             None => {
                                 // Just pass it through
                                 self.cancel_pending_tap();
-                                self.write_event_and_sync(event)?;
+                                self.write_event(event)?;
                             }
                         }
                     }
                     KeyEventType::Unknown(_) => {
-                        self.write_event_and_sync(event)?;
+                        self.write_event(event)?;
                     }
                 };
             }
-            _ => {  // All other event types, assume provides value
-                match self.lookup_mapping(code.clone(), event.value) {
+            _ => {
+                // All other event types, assume provides value
+                let mapping = self.lookup_mapping(code, event.value);
+                match mapping.map(|idx| &rules.mappings[idx]) {
                     Some(Mapping::Remap { input, output, .. }) => {
-                        match input.iter().find(|wrapper| wrapper.code == event.event_code && (wrapper.scale == 0 || wrapper.scale.is_negative() == event.value.is_negative())) {
-                            Some(input_wrapper) => {
-                                for k in output {
-                                    let out_val = match k.code {
-                                        EventCode::EV_KEY(_) => KeyEventType::Press.value(),
-                                        _ => (event.value / (if input_wrapper.scale == 0 {1} else {input_wrapper.scale})) * (if k.scale == 0 {1} else {k.scale}),
-                                    };
-                                    self.write_event(&InputEvent::new(&event.time, &k.code, out_val)).expect("Failed to write event");
-                                    if let EventCode::EV_KEY(_) = k.code {
-                                        self.write_event(&InputEvent::new(&event.time, &k.code, KeyEventType::Release.value())).expect("Failed to write event");
-                                    }
+                        if let Some(input_wrapper) = input.iter().find(|wrapper| {
+                            wrapper.code == event.event_code
+                                && (wrapper.scale == 0
+                                    || wrapper.scale.is_negative() == event.value.is_negative())
+                        }) {
+                            let mut pressed = false;
+                            for k in output.iter().filter(|k| !is_virtual_code(&k.code)) {
+                                let out_val = output_value(input_wrapper, k, event.value);
+                                self.write_event(&InputEvent::new(&event.time, &k.code, out_val))?;
+                                pressed |= matches!(k.code, EventCode::EV_KEY(_));
+                            }
+                            if pressed {
+                                // As with a tap, the keys are released in
+                                // a frame after the one that presses them
+                                self.flush_frame(&event.time)?;
+                                for k in output
+                                    .iter()
+                                    .filter(|k| matches!(k.code, EventCode::EV_KEY(_)))
+                                {
+                                    self.write_event(&make_event(
+                                        k.code,
+                                        &event.time,
+                                        KeyEventType::Release,
+                                    ))?;
                                 }
-                                self.generate_sync_event(&event.time)?;
-                            },
-                            None => {}
+                            }
                         }
                     }
                     _ => {
                         // Just pass it through
                         self.cancel_pending_tap();
-                        self.write_event_and_sync(event)?;
+                        self.write_event(event)?;
                     }
                 }
             }
@@ -415,28 +1377,23 @@ impl InputMapper {
         time: &TimeVal,
         event_type: KeyEventType,
     ) -> Result<()> {
-        for k in key {
-            let event = make_event(k.clone(), time, event_type);
+        for k in key.iter().filter(|k| !is_virtual_code(k)) {
+            let event = make_event(*k, time, event_type);
             self.write_event(&event)?;
         }
-        self.generate_sync_event(time)?;
-        Ok(())
-    }
-
-    fn write_event_and_sync(&mut self, event: &InputEvent) -> Result<()> {
-        self.write_event(event)?;
-        self.generate_sync_event(&event.time)?;
         Ok(())
     }
 
+    /// Add `event` to the current output frame, keeping track of the
+    /// keys that the output device is holding
     fn write_event(&mut self, event: &InputEvent) -> Result<()> {
         log::trace!("OUT: {:?}", event);
-        self.output.write_event(&event)?;
-        if let EventCode::EV_KEY(_) = event.event_code {
+        self.frames[self.frame_input].push(event.clone());
+        if is_key_code(&event.event_code) {
             let event_type = KeyEventType::from_value(event.value);
             match event_type {
                 KeyEventType::Press | KeyEventType::Repeat => {
-                    self.output_keys.insert(event.event_code.clone());
+                    self.output_keys.insert(event.event_code);
                 }
                 KeyEventType::Release => {
                     self.output_keys.remove(&event.event_code);
@@ -447,61 +1404,831 @@ impl InputMapper {
         Ok(())
     }
 
-    fn generate_sync_event(&self, time: &TimeVal) -> Result<()> {
+    /// Send the events of the current output frame followed by a
+    /// `SYN_REPORT`.  Nothing is sent if the frame is empty, such as
+    /// when the mappings swallowed all of an input frame.
+    fn flush_frame(&mut self, time: &TimeVal) -> Result<()> {
+        let frame = &mut self.frames[self.frame_input];
+        if frame.is_empty() {
+            return Ok(());
+        }
+        for event in frame.drain(..) {
+            self.output.write_event(&event)?;
+        }
         self.output.write_event(&InputEvent::new(
             time,
-            &EventCode::EV_SYN(evdev_rs::enums::EV_SYN::SYN_REPORT),
+            &EventCode::EV_SYN(EV_SYN::SYN_REPORT),
             0,
         ))?;
         Ok(())
     }
+
+    /// Send the events of every input's output frame, such as before
+    /// the output device is replaced
+    fn flush_all_frames(&mut self, time: &TimeVal) -> Result<()> {
+        let current = self.frame_input;
+        let flushed = (0..self.frames.len()).try_for_each(|idx| {
+            self.frame_input = idx;
+            self.flush_frame(time)
+        });
+        self.frame_input = current;
+        flushed
+    }
+}
+
+impl Drop for InputMapper {
+    fn drop(&mut self) {
+        self.release_all();
+    }
+}
+
+/// Computes the event types that the mappings need to process,
+/// along with the codes that they can emit
+fn mapping_requirements(mappings: &[Mapping]) -> (HashSet<EventType>, HashSet<KeyCode>) {
+    let mut mapped_types = HashSet::new();
+    let mut output_codes = HashSet::new();
+    for map in mappings {
+        match map {
+            Mapping::DualRole {
+                input, tap, hold, ..
+            } => {
+                for i in input {
+                    mapped_types.insert(input_event_type(i));
+                }
+                output_codes.extend(tap.iter().cloned());
+                output_codes.extend(hold.iter().cloned());
+            }
+            Mapping::Remap { input, output, .. } => {
+                for i in input {
+                    mapped_types.insert(input_event_type(&i.code));
+                }
+                output_codes.extend(output.iter().map(|o| o.code));
+            }
+        }
+    }
+    output_codes.retain(|code| !is_virtual_code(code));
+    (mapped_types, output_codes)
+}
+
+/// The type of the events that are matched by an input `code`.
+/// Scancodes are matched with the key event that follows them, and
+/// virtual modifiers are held by way of key events.
+fn input_event_type(code: &KeyCode) -> EventType {
+    if as_scan_code(code).is_some() || is_virtual_code(code) {
+        EventType::EV_KEY
+    } else {
+        to_event_type(code)
+    }
+}
+
+/// Collects the `MSC_SCAN` scancodes that the mappings refer to
+fn mapping_scan_codes(mappings: &[Mapping]) -> HashSet<u32> {
+    let mut scan_codes = HashSet::new();
+    for map in mappings {
+        match map {
+            Mapping::DualRole { input, .. } => {
+                scan_codes.extend(input.iter().filter_map(as_scan_code))
+            }
+            Mapping::Remap { input, .. } => {
+                scan_codes.extend(input.iter().filter_map(|i| as_scan_code(&i.code)))
+            }
+        }
+    }
+    scan_codes
+}
+
+fn now() -> TimeVal {
+    let since_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    TimeVal::new(
+        since_epoch.as_secs() as libc::time_t,
+        since_epoch.subsec_micros() as libc::suseconds_t,
+    )
+}
+
+/// Receives a description of each step taken by `compute_keys`
+pub trait ComputeObserver {
+    /// The DualRole mapping at `idx` replaced its held input with `hold`
+    fn dual_role(&mut self, _idx: usize, _input: &[KeyCode], _hold: &[KeyCode]) {}
+    /// The DualRole mapping at `idx` left its held input alone, because
+    /// the keys in `unless` were held when it was pressed
+    fn dual_role_unless(&mut self, _idx: usize, _input: &[KeyCode], _unless: Keys) {}
+    /// The Remap mapping at `idx` matched, and released the held keys
+    /// in `released` because of its `modifiers` policy or its `mask`
+    fn remap_matched(&mut self, _idx: usize, _released: Keys) {}
+    /// The Remap mapping at `idx` did not match; `available` is the set
+    /// of keys that had not been consumed by earlier Remap mappings.
+    /// Mappings that have none of the available keys as an input are
+    /// not reported.
+    fn remap_skipped(&mut self, _idx: usize, _available: Keys) {}
+}
+
+impl ComputeObserver for () {}
+
+/// Insert the side-agnostic modifiers for which either key is in `keys`
+pub fn add_logical_modifiers(keys: &mut HashSet<KeyCode>) {
+    for logical in logical_modifiers() {
+        if let Some(sides) = logical_modifier_keys(&logical) {
+            if sides.iter().any(|k| keys.contains(k)) {
+                keys.insert(logical);
+            }
+        }
+    }
+}
+
+/// Returns true if `input` includes `code`, either directly or as
+/// one side of a side-agnostic modifier
+fn input_covers(input: &HashSet<KeyCodeWrapper>, code: &KeyCode) -> bool {
+    input.iter().any(|i| {
+        i.code == *code || logical_modifier_keys(&i.code).is_some_and(|sides| sides.contains(code))
+    })
+}
+
+/// The keys in `held` that are not part of `input`.  The side-agnostic
+/// modifiers are left out, as their keys are in `held`.
+pub fn extra_keys<'a>(
+    input: &'a HashSet<KeyCodeWrapper>,
+    held: &'a HashSet<KeyCode>,
+) -> impl Iterator<Item = KeyCode> + 'a {
+    held.iter()
+        .filter(move |code| logical_modifier_keys(code).is_none() && !input_covers(input, code))
+        .cloned()
+}
+
+/// The modifiers in `held` that are not part of `input`
+pub fn extra_modifiers<'a>(
+    input: &'a HashSet<KeyCodeWrapper>,
+    held: &'a HashSet<KeyCode>,
+    modifiers: &'a HashSet<KeyCode>,
+) -> impl Iterator<Item = KeyCode> + 'a {
+    extra_keys(input, held).filter(move |code| is_modifier_code(code, modifiers))
+}
+
+/// Compute the value to emit for the `output` of a Remap mapping
+/// that was triggered by a `value` for its `input`.  Keys are pressed;
+/// relative and absolute values are divided by the input scale and
+/// multiplied by the output scale.
+pub fn output_value(input: &KeyCodeWrapper, output: &KeyCodeWrapper, value: i32) -> i32 {
+    match output.code {
+        EventCode::EV_KEY(_) => KeyEventType::Press.value(),
+        _ => {
+            (value / (if input.scale == 0 { 1 } else { input.scale }))
+                * (if output.scale == 0 { 1 } else { output.scale })
+        }
+    }
 }
 
 fn make_event(key: KeyCode, time: &TimeVal, event_type: KeyEventType) -> InputEvent {
     InputEvent::new(time, &key, event_type.value())
 }
 
-fn is_modifier(key: &EV_KEY) -> bool {
-    match key {
+pub fn is_modifier(key: &EV_KEY) -> bool {
+    matches!(
+        key,
         EV_KEY::KEY_FN
-        | EV_KEY::KEY_LEFTALT
-        | EV_KEY::KEY_RIGHTALT
-        | EV_KEY::KEY_LEFTMETA
-        | EV_KEY::KEY_RIGHTMETA
-        | EV_KEY::KEY_LEFTCTRL
-        | EV_KEY::KEY_RIGHTCTRL
-        | EV_KEY::KEY_LEFTSHIFT
-        | EV_KEY::KEY_RIGHTSHIFT => true,
-        _ => false,
-    }
-}
-
-/// Orders modifier keys ahead of non-modifier keys.
-/// Unfortunately the underlying type doesn't allow direct
-/// comparison, but that's ok for our purposes.
-fn modifiers_first(a: &KeyCode, b: &KeyCode) -> Ordering {
-    let mut a_ismod = false;
-    let mut b_ismod = false;
-    if let EventCode::EV_KEY(k) = a {
-       a_ismod = is_modifier(&k);
-    }
-    if let EventCode::EV_KEY(k) = b {
-       b_ismod = is_modifier(&k);
-    }
-    if a_ismod && b_ismod {
-        if b_ismod {
-            Ordering::Equal
-        } else {
-            Ordering::Less
-        }
-    } else if b_ismod {
-        Ordering::Greater
-    } else {
-        // Neither are modifiers
-        Ordering::Equal
+            | EV_KEY::KEY_LEFTALT
+            | EV_KEY::KEY_RIGHTALT
+            | EV_KEY::KEY_LEFTMETA
+            | EV_KEY::KEY_RIGHTMETA
+            | EV_KEY::KEY_LEFTCTRL
+            | EV_KEY::KEY_RIGHTCTRL
+            | EV_KEY::KEY_LEFTSHIFT
+            | EV_KEY::KEY_RIGHTSHIFT
+    )
+}
+
+/// Returns true if `code` is one of the built-in modifier keys, a
+/// virtual modifier, or one of the configured `modifiers`
+pub fn is_modifier_code(code: &KeyCode, modifiers: &HashSet<KeyCode>) -> bool {
+    match code {
+        EventCode::EV_KEY(key) if is_modifier(key) => true,
+        code => is_virtual_code(code) || modifiers.contains(code),
+    }
+}
+
+/// Where `key` comes in the order in which keys are pressed: the keys
+/// in `ordered` in that order, then the modifiers, then the rest.
+/// Keys are released in the reverse order.
+pub fn press_rank(key: &KeyCode, modifiers: &HashSet<KeyCode>, ordered: &[KeyCode]) -> (u8, usize) {
+    match ordered.iter().position(|k| k == key) {
+        Some(pos) => (0, pos),
+        None if is_modifier_code(key, modifiers) => (1, 0),
+        None => (2, 0),
     }
 }
 
-fn modifiers_last(a: &KeyCode, b: &KeyCode) -> Ordering {
-    modifiers_first(a, b).reverse()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev_rs::enums::EV_KEY::*;
+    use std::cell::RefCell;
+
+    /// The events of each frame, as their code and value
+    type Frames = Vec<Vec<(KeyCode, i32)>>;
+
+    /// Records the frames that are written to the output device
+    #[derive(Clone, Default)]
+    struct Recorder {
+        frames: Rc<RefCell<Frames>>,
+        pending: Rc<RefCell<Vec<(KeyCode, i32)>>>,
+    }
+
+    impl OutputDevice for Recorder {
+        fn write_event(&self, event: &InputEvent) -> std::io::Result<()> {
+            if event.event_code == EventCode::EV_SYN(EV_SYN::SYN_REPORT) {
+                let frame = self.pending.take();
+                self.frames.borrow_mut().push(frame);
+            } else {
+                self.pending
+                    .borrow_mut()
+                    .push((event.event_code, event.value));
+            }
+            std::io::Result::Ok(())
+        }
+    }
+
+    /// A mapper without input devices for the configuration `toml`,
+    /// as if it had grabbed two inputs while the `held` keys were held
+    fn mapper(name: &str, toml: &str, held: &[KeyCode]) -> (InputMapper, Recorder) {
+        let config = MappingConfig::from_toml(name, toml);
+        let recorder = Recorder::default();
+        let null = std::fs::File::open("/dev/null").unwrap();
+        let mapper = InputMapper::new(
+            vec![],
+            vec![
+                PathBuf::from("/dev/input/event0"),
+                PathBuf::from("/dev/input/event1"),
+            ],
+            Box::new(recorder.clone()),
+            config,
+            HashSet::new(),
+            held.to_vec(),
+            OwnedFd::from(null),
+        )
+        .unwrap();
+        (mapper, recorder)
+    }
+
+    /// Feed `events` to `mapper` as if the input at `idx` reported
+    /// them, without ending its frame
+    fn feed(mapper: &mut InputMapper, idx: usize, events: &[(KeyCode, i32)]) {
+        let time = TimeVal::new(1, 0);
+        for (code, value) in events {
+            assert!(!mapper
+                .process_event(idx, InputEvent::new(&time, code, *value))
+                .unwrap());
+        }
+    }
+
+    /// Feed `events` to `mapper` as one frame of the first input,
+    /// returning the frames that it wrote
+    fn send(mapper: &mut InputMapper, output: &Recorder, events: &[(KeyCode, i32)]) -> Frames {
+        let syn = EventCode::EV_SYN(EV_SYN::SYN_REPORT);
+        feed(mapper, 0, events);
+        feed(mapper, 0, &[(syn, 0)]);
+        output.frames.take()
+    }
+
+    #[test]
+    fn keys_held_at_grab_are_ignored_until_released() {
+        let (caps, a, esc) = (
+            EventCode::EV_KEY(KEY_CAPSLOCK),
+            EventCode::EV_KEY(KEY_A),
+            EventCode::EV_KEY(KEY_ESC),
+        );
+        let toml = r#"
+            [[remap]]
+            input = ["KEY_CAPSLOCK"]
+            output = ["KEY_ESC"]
+        "#;
+        let (mut mapper, output) = mapper("grab-seeded", toml, &[caps]);
+        assert_eq!(mapper.input_state, [caps]);
+        // Other keys are mapped as usual while it is held
+        assert_eq!(send(&mut mapper, &output, &[(a, 1)]), [[(a, 1)]]);
+        assert_eq!(send(&mut mapper, &output, &[(a, 0)]), [[(a, 0)]]);
+        // Neither its repeats nor its release reach the output
+        assert_eq!(send(&mut mapper, &output, &[(caps, 2)]), Frames::new());
+        assert_eq!(send(&mut mapper, &output, &[(caps, 0)]), Frames::new());
+        assert!(mapper.input_state.is_empty());
+        // Once it has been released, it is mapped
+        assert_eq!(send(&mut mapper, &output, &[(caps, 1)]), [[(esc, 1)]]);
+        assert_eq!(send(&mut mapper, &output, &[(caps, 0)]), [[(esc, 0)]]);
+    }
+
+    #[test]
+    fn keys_held_when_grabbed_are_not_pressed() {
+        let config = MappingConfig::from_toml(
+            "held",
+            r#"
+            [[remap]]
+            input = ["KEY_CAPSLOCK"]
+            output = ["KEY_ESC"]
+            "#,
+        );
+        let rules = RuleSet::new(config.mappings, config.modifiers).unwrap();
+        let scanned_keys = HashMap::new();
+        let (caps, a) = (EventCode::EV_KEY(KEY_CAPSLOCK), EventCode::EV_KEY(KEY_A));
+        let mut tracker = KeyTracker::default();
+        tracker.ignore_until_released(&[caps]);
+        let mut input_state = vec![caps, a];
+        let mut output_keys = HashSet::new();
+        tracker.compute_changes(&rules, &input_state, &scanned_keys, &output_keys);
+        assert_eq!(tracker.to_press, [a]);
+        output_keys.insert(a);
+
+        // A reload doesn't make it count as held
+        tracker.reset(&input_state);
+        tracker.compute_changes(&rules, &input_state, &scanned_keys, &output_keys);
+        assert!(tracker.to_press.is_empty());
+        assert!(tracker.to_release.is_empty());
+
+        // Nor does its release reach the output
+        input_state.retain(|k| *k != caps);
+        tracker.released(&caps);
+        tracker.compute_changes(&rules, &input_state, &scanned_keys, &output_keys);
+        assert!(tracker.to_press.is_empty());
+        assert!(tracker.to_release.is_empty());
+
+        // Once it has been released, it is mapped as usual
+        input_state.push(caps);
+        tracker.compute_changes(&rules, &input_state, &scanned_keys, &output_keys);
+        assert_eq!(tracker.to_press, [EventCode::EV_KEY(KEY_ESC)]);
+    }
+
+    #[test]
+    fn each_input_frame_is_one_output_frame() {
+        let (rel_x, rel_y, btn) = (
+            EventCode::EV_REL(EV_REL::REL_X),
+            EventCode::EV_REL(EV_REL::REL_Y),
+            EventCode::EV_KEY(BTN_LEFT),
+        );
+        let (a, b) = (EventCode::EV_KEY(KEY_A), EventCode::EV_KEY(KEY_B));
+        let syn = EventCode::EV_SYN(EV_SYN::SYN_REPORT);
+        let toml = r#"
+            [[remap]]
+            input = ["KEY_A"]
+            output = ["KEY_B"]
+        "#;
+        let (mut mapper, output) = mapper("frames", toml, &[]);
+        assert_eq!(
+            send(&mut mapper, &output, &[(rel_x, 3), (rel_y, -2), (btn, 1)]),
+            [[(rel_x, 3), (rel_y, -2), (btn, 1)]]
+        );
+
+        // A frame of the other input that arrives in the middle of one
+        // doesn't split it
+        feed(&mut mapper, 1, &[(rel_x, 1)]);
+        feed(&mut mapper, 0, &[(a, 1), (syn, 0)]);
+        assert_eq!(output.frames.take(), [[(b, 1)]]);
+        feed(&mut mapper, 1, &[(rel_y, 1), (btn, 0), (syn, 0)]);
+        assert_eq!(output.frames.take(), [[(rel_x, 1), (rel_y, 1), (btn, 0)]]);
+    }
+
+    #[test]
+    fn repeats_follow_the_keys_held_now() {
+        let (a, b, x) = (
+            EventCode::EV_KEY(KEY_A),
+            EventCode::EV_KEY(KEY_B),
+            EventCode::EV_KEY(KEY_X),
+        );
+        let shift = EventCode::EV_KEY(KEY_LEFTSHIFT);
+        let toml = r#"
+            [[remap]]
+            input = ["SHIFT", "KEY_A"]
+            output = ["KEY_X"]
+
+            [[remap]]
+            input = ["KEY_A"]
+            output = ["KEY_B"]
+        "#;
+        let (mut mapper, output) = mapper("repeats", toml, &[]);
+        assert_eq!(send(&mut mapper, &output, &[(a, 1)]), [[(b, 1)]]);
+        assert_eq!(send(&mut mapper, &output, &[(a, 2)]), [[(b, 2)]]);
+        // SHIFT, pressed after KEY_A, changes what it repeats as well
+        // as what it outputs
+        assert_eq!(
+            send(&mut mapper, &output, &[(shift, 1)]),
+            [[(b, 0), (x, 1)]]
+        );
+        assert_eq!(send(&mut mapper, &output, &[(a, 2)]), [[(x, 2)]]);
+    }
+
+    #[test]
+    fn kill_switch_toggles_passthrough() {
+        let (lshift, rshift, esc) = (
+            EventCode::EV_KEY(KEY_LEFTSHIFT),
+            EventCode::EV_KEY(KEY_RIGHTSHIFT),
+            EventCode::EV_KEY(KEY_ESC),
+        );
+        let (a, b) = (EventCode::EV_KEY(KEY_A), EventCode::EV_KEY(KEY_B));
+        let toml = r#"
+            [[remap]]
+            input = ["KEY_A"]
+            output = ["KEY_B"]
+        "#;
+        let (mut mapper, output) = mapper("kill-switch-toggle", toml, &[]);
+        assert_eq!(send(&mut mapper, &output, &[(lshift, 1)]), [[(lshift, 1)]]);
+        assert_eq!(send(&mut mapper, &output, &[(rshift, 1)]), [[(rshift, 1)]]);
+        // Completing the chord releases the output, and swallows ESC
+        assert_eq!(
+            send(&mut mapper, &output, &[(esc, 1)]),
+            [[(rshift, 0), (lshift, 0)]]
+        );
+        assert!(mapper.passthrough);
+        assert_eq!(mapper.input_state, [lshift, rshift]);
+        assert_eq!(send(&mut mapper, &output, &[(esc, 2)]), Frames::new());
+        assert_eq!(send(&mut mapper, &output, &[(esc, 0)]), Frames::new());
+        // The SHIFT keys were released on the output already
+        assert_eq!(send(&mut mapper, &output, &[(lshift, 0)]), Frames::new());
+        assert_eq!(send(&mut mapper, &output, &[(rshift, 0)]), Frames::new());
+        // Other keys pass through unchanged
+        assert_eq!(send(&mut mapper, &output, &[(a, 1)]), [[(a, 1)]]);
+        assert_eq!(send(&mut mapper, &output, &[(a, 0)]), [[(a, 0)]]);
+
+        // Pressing it again applies the mappings again
+        send(&mut mapper, &output, &[(lshift, 1)]);
+        send(&mut mapper, &output, &[(rshift, 1)]);
+        // Their press order isn't known in passthrough mode, so they
+        // are released in any order
+        let mut released = send(&mut mapper, &output, &[(esc, 1)]);
+        released[0].sort_by_key(|(k, _)| code_name(k));
+        assert_eq!(released, [[(lshift, 0), (rshift, 0)]]);
+        assert!(!mapper.passthrough);
+        assert_eq!(send(&mut mapper, &output, &[(esc, 0)]), Frames::new());
+        assert_eq!(send(&mut mapper, &output, &[(lshift, 0)]), Frames::new());
+        assert_eq!(send(&mut mapper, &output, &[(rshift, 0)]), Frames::new());
+        assert!(mapper.input_state.is_empty());
+        assert_eq!(send(&mut mapper, &output, &[(a, 1)]), [[(b, 1)]]);
+        assert_eq!(send(&mut mapper, &output, &[(a, 0)]), [[(b, 0)]]);
+    }
+
+    #[test]
+    fn kill_switch_exits() {
+        let (ctrl, backspace) = (
+            EventCode::EV_KEY(KEY_LEFTCTRL),
+            EventCode::EV_KEY(KEY_BACKSPACE),
+        );
+        let (caps, esc) = (EventCode::EV_KEY(KEY_CAPSLOCK), EventCode::EV_KEY(KEY_ESC));
+        let toml = r#"
+            [kill_switch]
+            keys = "KEY_LEFTCTRL+KEY_BACKSPACE"
+            action = "exit"
+            [[remap]]
+            input = ["KEY_CAPSLOCK"]
+            output = ["KEY_ESC"]
+        "#;
+        let (mut mapper, output) = mapper("kill-switch-exit", toml, &[]);
+        assert_eq!(send(&mut mapper, &output, &[(caps, 1)]), [[(esc, 1)]]);
+        assert_eq!(send(&mut mapper, &output, &[(ctrl, 1)]), [[(ctrl, 1)]]);
+        let time = TimeVal::new(1, 0);
+        assert!(mapper
+            .process_event(0, InputEvent::new(&time, &backspace, 1))
+            .unwrap());
+        // Nothing is written until the mapper releases everything on
+        // its way out
+        assert_eq!(output.frames.take(), Frames::new());
+        mapper.release_all();
+        assert_eq!(output.frames.take(), [[(esc, 0), (ctrl, 0)]]);
+    }
+
+    /// Press `key` on the output device behind the back of the
+    /// mappings, as if a release had been lost
+    fn leak(mapper: &mut InputMapper, output: &Recorder, key: KeyCode) {
+        let time = TimeVal::new(1, 0);
+        mapper
+            .emit_keys(&[key], &time, KeyEventType::Press)
+            .unwrap();
+        mapper.flush_frame(&time).unwrap();
+        assert_eq!(output.frames.take(), [[(key, 1)]]);
+    }
+
+    const STUCK_CONFIG: &str = r#"
+        [[dual_role]]
+        input = "KEY_CAPSLOCK"
+        hold = ["KEY_LEFTCTRL"]
+        tap = ["KEY_ESC"]
+    "#;
+
+    #[test]
+    fn stuck_keys_are_released_with_the_last_key() {
+        let (ctrl, a, b) = (
+            EventCode::EV_KEY(KEY_LEFTCTRL),
+            EventCode::EV_KEY(KEY_A),
+            EventCode::EV_KEY(KEY_B),
+        );
+        let (mut mapper, output) = mapper("stuck-release", STUCK_CONFIG, &[]);
+        let origin = &mapper.origins[0];
+        assert_eq!(
+            mapper.stuck_key_warning(&ctrl),
+            format!(
+                "KEY_LEFTCTRL is stuck on the output device; releasing it. \
+                 It is an output of {}",
+                origin
+            )
+        );
+        assert_eq!(
+            mapper.stuck_key_warning(&b),
+            "KEY_B is stuck on the output device; releasing it. \
+             It is not the output of any rule"
+        );
+
+        send(&mut mapper, &output, &[(a, 1)]);
+        leak(&mut mapper, &output, ctrl);
+        assert_eq!(send(&mut mapper, &output, &[(a, 0)]), [[(ctrl, 0), (a, 0)]]);
+        assert!(mapper.output_keys.is_empty());
+
+        // The outputs of the held keys are not stuck
+        let caps = EventCode::EV_KEY(KEY_CAPSLOCK);
+        assert_eq!(send(&mut mapper, &output, &[(caps, 1)]), [[(ctrl, 1)]]);
+        assert_eq!(send(&mut mapper, &output, &[(a, 1)]), [[(a, 1)]]);
+        assert_eq!(send(&mut mapper, &output, &[(caps, 0)]), [[(ctrl, 0)]]);
+        assert_eq!(send(&mut mapper, &output, &[(a, 0)]), [[(a, 0)]]);
+    }
+
+    #[test]
+    fn the_watchdog_releases_stuck_keys() {
+        let (ctrl, caps, a) = (
+            EventCode::EV_KEY(KEY_LEFTCTRL),
+            EventCode::EV_KEY(KEY_CAPSLOCK),
+            EventCode::EV_KEY(KEY_A),
+        );
+        let (mut mapper, output) = mapper("stuck-watchdog", STUCK_CONFIG, &[]);
+        send(&mut mapper, &output, &[(a, 1)]);
+        leak(&mut mapper, &output, ctrl);
+        assert!(!mapper.run_watchdog().unwrap());
+        assert_eq!(output.frames.take(), [[(ctrl, 0)]]);
+        assert_eq!(mapper.output_keys, HashSet::from([a]));
+
+        // Keys that the held keys explain are left alone
+        send(&mut mapper, &output, &[(caps, 1)]);
+        assert!(!mapper.run_watchdog().unwrap());
+        assert_eq!(output.frames.take(), Frames::new());
+        assert_eq!(mapper.output_keys, HashSet::from([a, ctrl]));
+    }
+
+    /// Benchmarks, which are only built with `--cfg bench`
+    #[cfg(bench)]
+    mod bench {
+        use super::*;
+        use crate::rules::tests::linear;
+        use evdev_rs::enums::int_to_ev_key;
+        use std::hint::black_box;
+        use std::time::Instant;
+
+        /// The keys that the generated rules are written for
+        fn bench_keys() -> Vec<KeyCode> {
+            [2..=11, 16..=25, 30..=38, 44..=50, 59..=68]
+                .into_iter()
+                .flatten()
+                .map(|code| EventCode::EV_KEY(int_to_ev_key(code).unwrap()))
+                .collect()
+        }
+
+        /// A configuration of about 500 rules that uses each kind of rule
+        fn bench_config(keys: &[KeyCode]) -> String {
+            let mut toml = String::from(
+                r#"
+                [virtual_modifiers]
+                NAV = []
+
+                [[dual_role]]
+                input = "KEY_CAPSLOCK"
+                hold = ["NAV"]
+                tap = ["KEY_ESC"]
+                "#,
+            );
+            let policies = ["passthrough", "exact", "consume"];
+            for (i, key) in keys.iter().enumerate() {
+                let key = code_name(key);
+                let next = code_name(&keys[(i + 1) % keys.len()]);
+                let third = code_name(&keys[(i + 2) % keys.len()]);
+                let far = code_name(&keys[(i + 5) % keys.len()]);
+                toml += &format!(
+                    r#"
+                    [[remap]]
+                    input = ["CTRL", "{key}"]
+                    output = ["KEY_LEFTCTRL", "KEY_F{f}"]
+                    modifiers = "{policy}"
+
+                    [[remap]]
+                    input = ["CTRL", "SHIFT", "{key}"]
+                    output = ["KEY_LEFTSHIFT", "KEY_F{f}"]
+                    ordered = true
+
+                    [[remap]]
+                    input = ["ALT", "{key}"]
+                    output = ["KEY_UP"]
+                    mask = ["SHIFT"]
+
+                    [[remap]]
+                    input = ["SHIFT", "ALT", "{key}"]
+                    output = ["KEY_DOWN"]
+                    suppress = true
+
+                    [[remap]]
+                    input = ["CTRL", "ALT", "{key}"]
+                    output = ["KEY_DELETE"]
+                    modifiers = "consume"
+
+                    [[remap]]
+                    input = ["META", "{key}"]
+                    output = ["KEY_HOME"]
+                    unless = ["KEY_RIGHTCTRL"]
+
+                    [[remap]]
+                    input = ["NAV", "{key}"]
+                    output = ["KEY_LEFT"]
+
+                    [[remap]]
+                    input = ["{key}", "{next}"]
+                    output = ["KEY_ENTER"]
+
+                    [[remap]]
+                    input = ["{key}", "{next}", "{third}"]
+                    output = ["KEY_TAB"]
+
+                    [[remap]]
+                    input = ["{key}", "{far}"]
+                    output = ["KEY_BACKSPACE"]
+
+                    [[remap]]
+                    input = ["SCAN_0x{scan:x}"]
+                    output = ["KEY_LEFTCTRL"]
+                    "#,
+                    f = 13 + i % 12,
+                    policy = policies[i % policies.len()],
+                    scan = 0x70004 + i,
+                );
+                if i < 10 {
+                    toml += &format!(
+                        r#"
+                        [[dual_role]]
+                        input = "KEY_RIGHTALT+{key}"
+                        hold = ["KEY_RIGHTALT", "KEY_LEFTSHIFT"]
+                        tap = ["KEY_F13"]
+                        ordered = true
+                        "#
+                    );
+                }
+            }
+            toml += r#"
+                [[remap]]
+                input = ["REL_WHEEL+"]
+                output = ["KEY_VOLUMEUP"]
+
+                [[remap]]
+                input = ["CTRL", "REL_WHEEL-"]
+                output = ["KEY_VOLUMEDOWN"]
+
+                [[remap]]
+                input = ["NAV", "REL_X+2"]
+                output = ["REL_X+1"]
+                "#;
+            toml
+        }
+
+        /// A deterministic stream of `len` key presses and releases, given
+        /// as the key and whether it is pressed; up to four keys are held
+        /// at once
+        fn bench_events(keys: &[KeyCode], len: usize) -> Vec<(KeyCode, bool)> {
+            let mut pool = keys.to_vec();
+            pool.extend(
+                [
+                    KEY_LEFTCTRL,
+                    KEY_RIGHTCTRL,
+                    KEY_LEFTSHIFT,
+                    KEY_LEFTALT,
+                    KEY_LEFTMETA,
+                    KEY_CAPSLOCK,
+                    KEY_RIGHTALT,
+                ]
+                .map(EventCode::EV_KEY),
+            );
+            let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+            let mut next = move |n: usize| {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (seed >> 33) as usize % n
+            };
+            let mut held: Vec<KeyCode> = vec![];
+            let mut events = vec![];
+            while events.len() < len {
+                if held.is_empty() || (held.len() < 4 && next(3) != 0) {
+                    let key = pool[next(pool.len())];
+                    if !held.contains(&key) {
+                        held.push(key);
+                        events.push((key, true));
+                    }
+                } else {
+                    let key = held.remove(next(held.len()));
+                    events.push((key, false));
+                }
+            }
+            events
+        }
+
+        /// Prints how long each of `events` calls of `f` took on average
+        fn time(label: &str, events: usize, mut f: impl FnMut(usize)) {
+            let start = Instant::now();
+            for i in 0..events {
+                f(i);
+            }
+            let elapsed = start.elapsed().as_nanos() as f64 / events as f64;
+            println!("{:<32} {:>8.0} ns/event", label, elapsed);
+        }
+
+        /// Times the work that the mapper does for each event against a
+        /// generated configuration of about 500 rules, and the same rule
+        /// matching done by a linear scan over the rules.  Run it with
+        /// `RUSTFLAGS="--cfg bench" cargo test --release bench_mapper_path -- --nocapture`
+        #[test]
+        fn bench_mapper_path() {
+            let keys = bench_keys();
+            let config = MappingConfig::from_toml("bench", &bench_config(&keys));
+            let rules = RuleSet::new(config.mappings, config.modifiers).unwrap();
+            let events = bench_events(&keys, 1_000_000);
+            println!(
+                "{} rules, {} key events",
+                rules.mappings.len(),
+                events.len()
+            );
+
+            let scanned_keys = HashMap::new();
+            let mut tracker = KeyTracker::default();
+            let mut input_state: Vec<KeyCode> = vec![];
+            let mut output_keys = HashSet::new();
+            time("key events", events.len(), |i| {
+                let (code, press) = events[i];
+                if press {
+                    input_state.push(code);
+                    black_box(rules.lookup_mapping(&input_state, code, 1));
+                } else {
+                    input_state.retain(|k| *k != code);
+                    tracker.released(&code);
+                }
+                tracker.compute_changes(&rules, &input_state, &scanned_keys, &output_keys);
+                for key in &tracker.to_release {
+                    output_keys.remove(key);
+                }
+                output_keys.extend(tracker.to_press.iter());
+            });
+
+            // The linear scan is too slow for the whole stream
+            let events = &events[..100_000];
+            let mut input_state: Vec<KeyCode> = vec![];
+            time("rule matching, indexed", events.len(), |i| {
+                let (code, press) = events[i];
+                if press {
+                    input_state.push(code);
+                    black_box(rules.lookup_mapping(&input_state, code, 1));
+                } else {
+                    input_state.retain(|k| *k != code);
+                }
+                black_box(rules.compute_keys_in_order(&input_state));
+            });
+            let mut input_state: Vec<KeyCode> = vec![];
+            time("rule matching, linear scan", events.len(), |i| {
+                let (code, press) = events[i];
+                if press {
+                    input_state.push(code);
+                    black_box(linear::lookup_mapping(&rules, &input_state, code, 1));
+                } else {
+                    input_state.retain(|k| *k != code);
+                }
+                black_box(linear::compute_keys_in_order(&rules, &input_state));
+            });
+
+            let motion = [
+                EventCode::EV_REL(EV_REL::REL_X),
+                EventCode::EV_REL(EV_REL::REL_Y),
+            ];
+            let mut input_state: Vec<KeyCode> = vec![];
+            time("mouse motion", events.len(), |i| {
+                let (code, press) = events[i];
+                if press {
+                    input_state.push(code);
+                } else {
+                    input_state.retain(|k| *k != code);
+                }
+                black_box(rules.lookup_mapping(&input_state, motion[i % 2], 1));
+            });
+            let mut input_state: Vec<KeyCode> = vec![];
+            time("mouse motion, linear scan", events.len(), |i| {
+                let (code, press) = events[i];
+                if press {
+                    input_state.push(code);
+                } else {
+                    input_state.retain(|k| *k != code);
+                }
+                black_box(linear::lookup_mapping(
+                    &rules,
+                    &input_state,
+                    motion[i % 2],
+                    1,
+                ));
+            });
+        }
+    }
 }
@@ -0,0 +1,1267 @@
+use crate::mapping::*;
+use crate::remapper::{is_modifier_code, press_rank, ComputeObserver};
+use evdev_rs::enums::EV_KEY;
+use evdev_rs::util::int_to_event_code;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+/// Every EV_KEY code has a slot of its own, numbered by its code;
+/// this includes the codes that evdev-rs has no name for
+const KEY_SLOTS: usize = EV_KEY::KEY_MAX as usize + 1;
+/// Slots for the other codes that a configuration refers to: its
+/// scancodes, virtual and side-agnostic modifiers, and REL and ABS axes
+const EXTRA_SLOTS: usize = 256;
+const WORDS: usize = (KEY_SLOTS + EXTRA_SLOTS).div_ceil(64);
+
+/// A set of codes, stored as a bitset over the slots of a `CodeSpace`
+/// so that it can be copied and combined without allocating
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct KeySet([u64; WORDS]);
+
+impl Default for KeySet {
+    fn default() -> Self {
+        Self([0; WORDS])
+    }
+}
+
+impl KeySet {
+    fn insert(&mut self, slot: usize) {
+        self.0[slot / 64] |= 1 << (slot % 64);
+    }
+
+    fn contains(&self, slot: usize) -> bool {
+        self.0[slot / 64] & (1 << (slot % 64)) != 0
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.iter().all(|word| *word == 0)
+    }
+
+    fn is_subset(&self, other: &KeySet) -> bool {
+        self.0.iter().zip(&other.0).all(|(a, b)| a & !b == 0)
+    }
+
+    fn intersects(&self, other: &KeySet) -> bool {
+        self.0.iter().zip(&other.0).any(|(a, b)| a & b != 0)
+    }
+
+    fn union(mut self, other: &KeySet) -> KeySet {
+        for (a, b) in self.0.iter_mut().zip(&other.0) {
+            *a |= b;
+        }
+        self
+    }
+
+    fn intersection(mut self, other: &KeySet) -> KeySet {
+        for (a, b) in self.0.iter_mut().zip(&other.0) {
+            *a &= b;
+        }
+        self
+    }
+
+    fn difference(mut self, other: &KeySet) -> KeySet {
+        for (a, b) in self.0.iter_mut().zip(&other.0) {
+            *a &= !b;
+        }
+        self
+    }
+
+    fn slots(self) -> impl Iterator<Item = usize> {
+        self.0.into_iter().enumerate().flat_map(|(idx, mut word)| {
+            std::iter::from_fn(move || {
+                if word == 0 {
+                    return None;
+                }
+                let bit = word.trailing_zeros() as usize;
+                word &= word - 1;
+                Some(idx * 64 + bit)
+            })
+        })
+    }
+}
+
+/// Assigns each code that a configuration can hold a slot in a `KeySet`
+#[derive(Debug, Default)]
+struct CodeSpace {
+    extras: Vec<KeyCode>,
+    extra_slots: HashMap<KeyCode, usize>,
+}
+
+impl CodeSpace {
+    fn slot(&self, code: &KeyCode) -> Option<usize> {
+        match code {
+            EventCode::EV_KEY(key) => Some(*key as usize),
+            EventCode::EV_UNK {
+                event_type,
+                event_code,
+            } if *event_type == EventType::EV_KEY as u32 && (*event_code as usize) < KEY_SLOTS => {
+                Some(*event_code as usize)
+            }
+            code => self.extra_slots.get(code).copied(),
+        }
+    }
+
+    fn add(&mut self, code: KeyCode) -> anyhow::Result<()> {
+        if self.slot(&code).is_some() {
+            return Ok(());
+        }
+        if self.extras.len() == EXTRA_SLOTS {
+            anyhow::bail!(
+                "the configuration refers to more than {} scancodes, virtual \
+                 modifiers and axes",
+                EXTRA_SLOTS
+            );
+        }
+        self.extra_slots.insert(code, KEY_SLOTS + self.extras.len());
+        self.extras.push(code);
+        Ok(())
+    }
+
+    fn code(&self, slot: usize) -> KeyCode {
+        match slot.checked_sub(KEY_SLOTS) {
+            Some(extra) => self.extras[extra],
+            None => int_to_event_code(EventType::EV_KEY as u32, slot as u32),
+        }
+    }
+
+    /// The set of `codes`.  Codes without a slot can't take part in
+    /// any rule, so they are left out.
+    fn set<'a>(&self, codes: impl IntoIterator<Item = &'a KeyCode>) -> KeySet {
+        let mut set = KeySet::default();
+        for slot in codes.into_iter().filter_map(|code| self.slot(code)) {
+            set.insert(slot);
+        }
+        set
+    }
+}
+
+/// A `KeySet` along with the codes that its slots stand for
+#[derive(Clone, Copy)]
+pub struct Keys<'a> {
+    set: KeySet,
+    space: &'a CodeSpace,
+}
+
+impl<'a> Keys<'a> {
+    pub fn iter(&self) -> impl Iterator<Item = KeyCode> + 'a {
+        let space = self.space;
+        self.set.slots().map(move |slot| space.code(slot))
+    }
+
+    pub fn contains(&self, code: &KeyCode) -> bool {
+        self.space
+            .slot(code)
+            .is_some_and(|slot| self.set.contains(slot))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty()
+    }
+
+    pub fn to_set(self) -> HashSet<KeyCode> {
+        self.iter().collect()
+    }
+}
+
+#[derive(Debug)]
+struct DualRoleRule {
+    /// The index of the mapping
+    idx: usize,
+    input: KeySet,
+    hold: KeySet,
+    unless: KeySet,
+    /// The inputs that stay silent until they are released once the
+    /// rule has applied: all of them, if the input is a chord
+    silenced: KeySet,
+}
+
+#[derive(Debug)]
+struct RemapRule {
+    /// The index of the mapping
+    idx: usize,
+    input: KeySet,
+    /// The inputs, along with both keys of the side-agnostic modifiers
+    /// among them; held keys outside of these are extras
+    covered: KeySet,
+    policy: ModifierPolicy,
+    /// The held keys that the `mask` releases
+    mask: KeySet,
+    unless: KeySet,
+    exact: bool,
+    output: KeySet,
+    /// The inputs that later rules can no longer use once this matches
+    consumed_inputs: KeySet,
+    /// The outputs that later rules can use as inputs
+    virtual_outputs: KeySet,
+    /// The outputs that later rules can't use as inputs
+    consumed_outputs: KeySet,
+    /// The inputs that stay silent until they are released once the
+    /// rule has applied, if it is a `suppress` rule
+    silenced: KeySet,
+    input_len: usize,
+}
+
+/// A rule that an event for a given code may match
+#[derive(Debug, Clone, Copy)]
+enum Candidate {
+    /// The position of the rule in `RuleSet::dual_roles`
+    DualRole(usize),
+    /// The position of the rule in `RuleSet::remaps`
+    Remap(usize),
+}
+
+/// The mappings of a configuration, compiled into bitsets and indexed
+/// by their input codes so that events can be matched against them
+/// without allocating
+#[derive(Debug)]
+pub struct RuleSet {
+    pub mappings: Vec<Mapping>,
+    /// Keys that count as modifiers in addition to the built-in ones
+    pub modifiers: HashSet<KeyCode>,
+    dual_roles: Vec<DualRoleRule>,
+    remaps: Vec<RemapRule>,
+    /// The rules that have each code as an input, in order
+    by_code: HashMap<KeyCode, Vec<Candidate>>,
+    /// For each slot, the positions in `remaps` of the rules that have
+    /// its code as an input, as a bitset; empty if there are none
+    remaps_by_slot: Vec<Vec<u64>>,
+    /// The positions in `remaps` of the rules without any inputs
+    remaps_without_input: Vec<u64>,
+    /// The positions in `remaps` of the rules that `compute` has to
+    /// consider, kept here so that it doesn't allocate for each event
+    candidates: RefCell<Vec<u64>>,
+    space: CodeSpace,
+    /// The side-agnostic modifiers, along with their keys
+    logical: Vec<(usize, KeySet)>,
+    logical_set: KeySet,
+    virtual_set: KeySet,
+    modifier_set: KeySet,
+}
+
+impl RuleSet {
+    pub fn new(mappings: Vec<Mapping>, modifiers: HashSet<KeyCode>) -> anyhow::Result<Self> {
+        let mut space = CodeSpace::default();
+        for code in logical_modifiers() {
+            space.add(code)?;
+        }
+        for map in &mappings {
+            let codes: Vec<KeyCode> = match map {
+                Mapping::DualRole {
+                    input,
+                    hold,
+                    tap,
+                    unless,
+                    ..
+                } => input
+                    .iter()
+                    .chain(hold)
+                    .chain(tap)
+                    .chain(unless)
+                    .cloned()
+                    .collect(),
+                Mapping::Remap {
+                    input,
+                    output,
+                    mask,
+                    unless,
+                    ..
+                } => input
+                    .iter()
+                    .chain(output)
+                    .map(|k| k.code)
+                    .chain(mask.iter().chain(unless).cloned())
+                    .collect(),
+            };
+            for code in codes {
+                space.add(code)?;
+            }
+        }
+
+        let logical = logical_modifiers()
+            .map(|code| {
+                let slot = space.slot(&code).expect("logical modifiers were added");
+                (
+                    slot,
+                    space.set(&with_sides(&[code]).skip(1).collect::<Vec<_>>()),
+                )
+            })
+            .collect();
+        let logical_set = space.set(&logical_modifiers().collect::<Vec<_>>());
+        let virtual_set = space.set(space.extras.iter().filter(|code| is_virtual_code(code)));
+        let mut modifier_set = KeySet::default();
+        for slot in 0..KEY_SLOTS + space.extras.len() {
+            if is_modifier_code(&space.code(slot), &modifiers) {
+                modifier_set.insert(slot);
+            }
+        }
+
+        let mut dual_roles = vec![];
+        let mut remaps = vec![];
+        let mut by_code: HashMap<KeyCode, Vec<Candidate>> = HashMap::new();
+        for (idx, map) in mappings.iter().enumerate() {
+            let (candidate, inputs): (Candidate, Vec<KeyCode>) = match map {
+                Mapping::DualRole {
+                    input,
+                    hold,
+                    unless,
+                    ..
+                } => {
+                    dual_roles.push(DualRoleRule {
+                        idx,
+                        input: space.set(input),
+                        hold: space.set(hold),
+                        unless: space.set(unless),
+                        silenced: if input.len() > 1 {
+                            space.set(input)
+                        } else {
+                            KeySet::default()
+                        },
+                    });
+                    (Candidate::DualRole(dual_roles.len() - 1), input.clone())
+                }
+                Mapping::Remap {
+                    input,
+                    output,
+                    modifiers: policy,
+                    mask,
+                    unless,
+                    exact,
+                    suppress,
+                    ..
+                } => {
+                    let codes: Vec<KeyCode> = input.iter().map(|i| i.code).collect();
+                    let outputs: Vec<KeyCode> = output.iter().map(|o| o.code).collect();
+                    let consumed = |code: &&KeyCode| {
+                        matches!(code, EventCode::EV_KEY(_)) && !is_modifier_code(code, &modifiers)
+                    };
+                    let silenced = |code: &&KeyCode| {
+                        *suppress
+                            && is_key_code(code)
+                            && !is_virtual_code(code)
+                            && !is_modifier_code(code, &modifiers)
+                    };
+                    let masked: Vec<KeyCode> = with_sides(mask)
+                        .filter(|code| !is_virtual_code(code))
+                        .collect();
+                    remaps.push(RemapRule {
+                        idx,
+                        input: space.set(&codes),
+                        covered: space.set(&with_sides(&codes).collect::<Vec<_>>()),
+                        policy: *policy,
+                        mask: space.set(&masked),
+                        unless: space.set(unless),
+                        exact: *exact,
+                        output: space.set(&outputs),
+                        consumed_inputs: space.set(codes.iter().filter(consumed)),
+                        virtual_outputs: space
+                            .set(outputs.iter().filter(|code| is_virtual_code(code))),
+                        consumed_outputs: space.set(outputs.iter().filter(consumed)),
+                        silenced: space.set(codes.iter().filter(silenced)),
+                        input_len: input.len(),
+                    });
+                    (Candidate::Remap(remaps.len() - 1), codes)
+                }
+            };
+            for code in inputs {
+                let candidates = by_code.entry(code).or_default();
+                // An input may be listed once for each direction
+                if !matches!(candidates.last(), Some(Candidate::Remap(pos)) if remaps[*pos].idx == idx)
+                {
+                    candidates.push(candidate);
+                }
+            }
+        }
+
+        let words = remaps.len().div_ceil(64);
+        let mut remaps_by_slot = vec![vec![]; KEY_SLOTS + space.extras.len()];
+        let mut remaps_without_input = vec![0; words];
+        for (pos, remap) in remaps.iter().enumerate() {
+            if remap.input.is_empty() {
+                remaps_without_input[pos / 64] |= 1 << (pos % 64);
+            }
+            for slot in remap.input.slots() {
+                let positions = &mut remaps_by_slot[slot];
+                positions.resize(words, 0);
+                positions[pos / 64] |= 1 << (pos % 64);
+            }
+        }
+
+        Ok(Self {
+            mappings,
+            modifiers,
+            dual_roles,
+            remaps,
+            by_code,
+            remaps_by_slot,
+            candidates: RefCell::new(remaps_without_input.clone()),
+            remaps_without_input,
+            space,
+            logical,
+            logical_set,
+            virtual_set,
+            modifier_set,
+        })
+    }
+
+    fn keys(&self, set: KeySet) -> Keys<'_> {
+        Keys {
+            set,
+            space: &self.space,
+        }
+    }
+
+    /// Insert the side-agnostic modifiers for which either key is in `keys`
+    fn add_logical_modifiers(&self, keys: &mut KeySet) {
+        for (slot, sides) in &self.logical {
+            if keys.intersects(sides) {
+                keys.insert(*slot);
+            }
+        }
+    }
+
+    /// The keys in `pressed` that were pressed before the last of the
+    /// `input` keys, along with their side-agnostic modifiers
+    fn held_before(&self, pressed: &[KeyCode], input: &KeySet) -> KeySet {
+        let last = pressed
+            .iter()
+            .rposition(|k| self.space.slot(k).is_some_and(|slot| input.contains(slot)))
+            .unwrap_or(pressed.len());
+        let mut held = self.space.set(&pressed[..last]).difference(input);
+        self.add_logical_modifiers(&mut held);
+        held
+    }
+
+    /// Replace the held inputs of the DualRole mappings with their hold
+    /// keys.  `pressed` is in the order that the keys were pressed, so
+    /// that `unless` can be checked against the keys that were held first.
+    /// The keys that the rules which apply silence are added to `silenced`.
+    fn apply_dual_roles(
+        &self,
+        pressed: &[KeyCode],
+        silenced: &mut KeySet,
+        observer: &mut impl ComputeObserver,
+    ) -> KeySet {
+        let mut keys = self.space.set(pressed);
+        for dual in &self.dual_roles {
+            let Mapping::DualRole { input, hold, .. } = &self.mappings[dual.idx] else {
+                continue;
+            };
+            if dual.input.is_subset(&keys) {
+                let unless = dual
+                    .unless
+                    .intersection(&self.held_before(pressed, &dual.input));
+                if !unless.is_empty() {
+                    observer.dual_role_unless(dual.idx, input, self.keys(unless));
+                    continue;
+                }
+                keys = keys.difference(&dual.input).union(&dual.hold);
+                *silenced = silenced.union(&dual.silenced);
+                observer.dual_role(dual.idx, input, hold);
+            }
+        }
+        keys
+    }
+
+    /// Compute the keys that the Remap mappings see when the `pressed`
+    /// keys are held: the DualRole mappings have been applied and the
+    /// held side-agnostic modifiers are included
+    fn active_keys(
+        &self,
+        pressed: &[KeyCode],
+        silenced: &mut KeySet,
+        observer: &mut impl ComputeObserver,
+    ) -> KeySet {
+        let mut keys = self.apply_dual_roles(pressed, silenced, observer);
+        self.add_logical_modifiers(&mut keys);
+        keys
+    }
+
+    /// Returns true if `rule` cannot match while the `held` keys are
+    /// held, because of its `modifiers` policy, its `unless` keys or
+    /// because it is `exact`.  Also returns the held modifiers that
+    /// are not part of its input.
+    fn remap_excluded(&self, rule: &RemapRule, held: &KeySet) -> (bool, KeySet) {
+        let extras = held.difference(&self.logical_set).difference(&rule.covered);
+        let extra_modifiers = extras.intersection(&self.modifier_set);
+        let excluded = (rule.policy == ModifierPolicy::Exact && !extra_modifiers.is_empty())
+            || held.intersects(&rule.unless)
+            || (rule.exact && !extras.is_empty());
+        (excluded, extra_modifiers)
+    }
+
+    /// Compute the effective set of keys that are pressed when the
+    /// `pressed` keys are held, given in the order that they were pressed
+    pub fn compute_keys(
+        &self,
+        pressed: &[KeyCode],
+        observer: &mut impl ComputeObserver,
+    ) -> Keys<'_> {
+        let (keys, _) = self.compute(pressed, observer);
+        self.keys(keys)
+    }
+
+    /// Add the Remap mappings that have the code of `slot` as an
+    /// input to `candidates`
+    fn add_candidates(&self, candidates: &mut [u64], slot: usize) {
+        for (word, positions) in candidates.iter_mut().zip(&self.remaps_by_slot[slot]) {
+            *word |= positions;
+        }
+    }
+
+    /// Compute the effective set of keys as `compute_keys` does, along
+    /// with the held keys that the rules which apply silence until
+    /// they are released.
+    /// Only the Remap mappings that have one of the available keys as
+    /// an input are considered, as no others can match.
+    fn compute(
+        &self,
+        pressed: &[KeyCode],
+        observer: &mut impl ComputeObserver,
+    ) -> (KeySet, KeySet) {
+        let mut silenced = KeySet::default();
+        // First phase is to apply any DualRole mappings as they are likely to
+        // be used to produce modifiers when held.
+        let mut keys = self.active_keys(pressed, &mut silenced, observer);
+        let mut available = keys;
+
+        // Second pass to apply Remap items.  The buffer is taken out
+        // rather than borrowed, as the observer may compute too.
+        let mut candidates = self.candidates.take();
+        candidates.clear();
+        candidates.extend_from_slice(&self.remaps_without_input);
+        for slot in available.slots() {
+            self.add_candidates(&mut candidates, slot);
+        }
+        let mut from = 0;
+        while let Some(pos) = next_set_bit(&candidates, from) {
+            from = pos + 1;
+            let remap = &self.remaps[pos];
+            // Most rules don't match, and that is the cheapest check
+            let (excluded, extra_modifiers) = if remap.input.is_subset(&available) {
+                self.remap_excluded(remap, &available)
+            } else {
+                (true, KeySet::default())
+            };
+            if !excluded {
+                // Held keys that the rule releases while it applies
+                let mut released = remap.mask.intersection(&keys);
+                if remap.policy == ModifierPolicy::Consume {
+                    released = released.union(&extra_modifiers);
+                }
+                // A side-agnostic modifier consumes whichever of its
+                // keys are held
+                keys = keys
+                    .difference(&released)
+                    .difference(&remap.covered)
+                    .union(&remap.output);
+                // Virtual modifiers are only useful as inputs for later
+                // remap rules, while other outputs that apply are not
+                // visible to them
+                available = available
+                    .difference(&remap.consumed_inputs)
+                    .union(&remap.virtual_outputs)
+                    .difference(&remap.consumed_outputs);
+                // The rules that use its virtual outputs may now match
+                for slot in remap.virtual_outputs.slots() {
+                    self.add_candidates(&mut candidates, slot);
+                }
+                silenced = silenced.union(&remap.silenced);
+                observer.remap_matched(remap.idx, self.keys(released));
+            } else {
+                observer.remap_skipped(remap.idx, self.keys(available));
+            }
+        }
+        self.candidates.replace(candidates);
+
+        (keys.difference(&self.virtual_set), silenced)
+    }
+
+    /// Compute the effective set of keys as `compute_keys` does,
+    /// in the order in which they should be pressed
+    pub fn compute_keys_in_order(&self, pressed: &[KeyCode]) -> Vec<KeyCode> {
+        let mut keys = vec![];
+        self.compute_keys_silencing(pressed, &mut keys, &mut vec![]);
+        keys
+    }
+
+    /// Compute the keys as `compute_keys_in_order` does, replacing the
+    /// contents of `keys` with them so that its allocation can be
+    /// reused.  `ordered` is scratch space for the outputs of the
+    /// `ordered` rules.  Returns the same keys as a set, along with
+    /// the held keys that the rules which apply keep silent until they
+    /// are released: the inputs of chorded DualRole mappings, and the
+    /// inputs of `suppress` Remap mappings other than modifiers.
+    pub fn compute_keys_silencing(
+        &self,
+        pressed: &[KeyCode],
+        keys: &mut Vec<KeyCode>,
+        ordered: &mut Vec<KeyCode>,
+    ) -> (Keys<'_>, Keys<'_>) {
+        ordered.clear();
+        let mut observer = OrderedOutputs {
+            mappings: &self.mappings,
+            keys: ordered,
+        };
+        let (set, silenced) = self.compute(pressed, &mut observer);
+        keys.clear();
+        keys.extend(self.keys(set).iter());
+        keys.sort_by_key(|key| press_rank(key, &self.modifiers, observer.keys.as_slice()));
+        (self.keys(set), self.keys(silenced))
+    }
+
+    /// Find the mapping that applies to an event for `code` with `value`,
+    /// given the `pressed` keys in the order that they were pressed.
+    /// Returns the index of the mapping.
+    pub fn lookup_mapping(&self, pressed: &[KeyCode], code: KeyCode, value: i32) -> Option<usize> {
+        // Most events, such as mouse movement, have no rules at all
+        let candidates = self.by_code.get(&code)?;
+        let slot = self.space.slot(&code)?;
+        let active = self.active_keys(pressed, &mut KeySet::default(), &mut ());
+        let mut held = active.union(&self.space.set(pressed));
+        held.insert(slot);
+
+        // We want the Remap with the most active keys
+        let mut best: Option<(usize, usize)> = None;
+        for candidate in candidates {
+            match *candidate {
+                Candidate::DualRole(pos) => {
+                    let dual = &self.dual_roles[pos];
+                    if dual.input.is_subset(&held)
+                        && !dual
+                            .unless
+                            .intersects(&self.held_before(pressed, &dual.input))
+                    {
+                        // A DualRole mapping has the highest precedence
+                        // so we've found our match
+                        return Some(dual.idx);
+                    }
+                }
+                Candidate::Remap(pos) => {
+                    let remap = &self.remaps[pos];
+                    let Mapping::Remap { input, .. } = &self.mappings[remap.idx] else {
+                        continue;
+                    };
+                    if self.remap_excluded(remap, &active).0 {
+                        continue;
+                    }
+                    // If part of a chord, all of its component keys
+                    // must also be pressed
+                    let code_matched = input.iter().any(|i| {
+                        i.code == code
+                            && match i.code {
+                                EventCode::EV_KEY(_) => true,
+                                _ => i.scale == 0 || i.scale.is_negative() == value.is_negative(),
+                            }
+                    });
+                    let better = best.is_none_or(|(_, len)| remap.input_len > len);
+                    if code_matched && better && remap.input.is_subset(&held) {
+                        best = Some((remap.idx, remap.input_len));
+                    }
+                }
+            }
+        }
+        best.map(|(idx, _)| idx)
+    }
+}
+
+/// The first position from `from` onwards that is set in the bitset
+/// `words`
+fn next_set_bit(words: &[u64], from: usize) -> Option<usize> {
+    let mut idx = from / 64;
+    let mut word = words.get(idx)? & (!0 << (from % 64));
+    while word == 0 {
+        idx += 1;
+        word = *words.get(idx)?;
+    }
+    Some(idx * 64 + word.trailing_zeros() as usize)
+}
+
+/// `codes`, each followed by the keys of the side-agnostic modifiers
+fn with_sides(codes: &[KeyCode]) -> impl Iterator<Item = KeyCode> + '_ {
+    codes.iter().flat_map(|code| {
+        std::iter::once(*code).chain(logical_modifier_keys(code).into_iter().flatten())
+    })
+}
+
+/// Collects the outputs of the `ordered` rules that apply,
+/// in the order that they are written
+struct OrderedOutputs<'a> {
+    mappings: &'a [Mapping],
+    keys: &'a mut Vec<KeyCode>,
+}
+
+impl<'a> ComputeObserver for OrderedOutputs<'a> {
+    fn dual_role(&mut self, idx: usize, _input: &[KeyCode], hold: &[KeyCode]) {
+        if let Mapping::DualRole { ordered: true, .. } = &self.mappings[idx] {
+            self.keys.extend(hold.iter().cloned());
+        }
+    }
+
+    fn remap_matched(&mut self, idx: usize, _released: Keys) {
+        if let Mapping::Remap {
+            output,
+            ordered: true,
+            ..
+        } = &self.mappings[idx]
+        {
+            self.keys.extend(output.iter().map(|o| o.code));
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::remapper::{add_logical_modifiers, extra_keys, extra_modifiers};
+    use evdev_rs::enums::EV_KEY::*;
+    use evdev_rs::enums::EV_REL;
+
+    /// The matcher as it was before the rules were indexed: a linear
+    /// scan over the mappings using sets of codes, which the indexed
+    /// lookups must agree with
+    pub(crate) mod linear {
+        use super::*;
+
+        fn held_before(pressed: &[KeyCode], input: &[KeyCode]) -> HashSet<KeyCode> {
+            let last = pressed
+                .iter()
+                .rposition(|k| input.contains(k))
+                .unwrap_or(pressed.len());
+            let mut held: HashSet<KeyCode> = pressed[..last]
+                .iter()
+                .filter(|k| !input.contains(k))
+                .cloned()
+                .collect();
+            add_logical_modifiers(&mut held);
+            held
+        }
+
+        fn unless_held(unless: &[KeyCode], held: &HashSet<KeyCode>) -> bool {
+            unless.iter().any(|k| held.contains(k))
+        }
+
+        fn apply_dual_roles(
+            mappings: &[Mapping],
+            pressed: &[KeyCode],
+            ordered: &mut Vec<KeyCode>,
+        ) -> HashSet<KeyCode> {
+            let mut keys: HashSet<KeyCode> = pressed.iter().cloned().collect();
+            for map in mappings {
+                if let Mapping::DualRole {
+                    input,
+                    hold,
+                    unless,
+                    ordered: in_order,
+                    ..
+                } = map
+                {
+                    if input.iter().all(|k| keys.contains(k))
+                        && !unless_held(unless, &held_before(pressed, input))
+                    {
+                        for k in input {
+                            keys.remove(k);
+                        }
+                        keys.extend(hold.iter().cloned());
+                        if *in_order {
+                            ordered.extend(hold.iter().cloned());
+                        }
+                    }
+                }
+            }
+            keys
+        }
+
+        fn remap_excluded(
+            map: &Mapping,
+            held: &HashSet<KeyCode>,
+            modifiers: &HashSet<KeyCode>,
+        ) -> bool {
+            match map {
+                Mapping::Remap {
+                    input,
+                    modifiers: policy,
+                    unless,
+                    exact,
+                    ..
+                } => {
+                    (*policy == ModifierPolicy::Exact
+                        && extra_modifiers(input, held, modifiers).next().is_some())
+                        || unless_held(unless, held)
+                        || (*exact && extra_keys(input, held).next().is_some())
+                }
+                Mapping::DualRole { .. } => false,
+            }
+        }
+
+        /// The keys, and the outputs of the `ordered` rules that apply
+        pub fn compute_keys(
+            rules: &RuleSet,
+            pressed: &[KeyCode],
+        ) -> (HashSet<KeyCode>, Vec<KeyCode>) {
+            let (mappings, modifiers) = (&rules.mappings, &rules.modifiers);
+            let mut ordered = vec![];
+            let mut keys = apply_dual_roles(mappings, pressed, &mut ordered);
+            add_logical_modifiers(&mut keys);
+            let mut available = keys.clone();
+            for map in mappings {
+                let Mapping::Remap {
+                    input,
+                    output,
+                    modifiers: policy,
+                    mask,
+                    ordered: in_order,
+                    ..
+                } = map
+                else {
+                    continue;
+                };
+                let extras: Vec<KeyCode> = extra_modifiers(input, &available, modifiers).collect();
+                if !input.iter().all(|i| available.contains(&i.code))
+                    || remap_excluded(map, &available, modifiers)
+                {
+                    continue;
+                }
+                let mut released = vec![];
+                if *policy == ModifierPolicy::Consume {
+                    released.extend(extras);
+                }
+                for m in mask {
+                    for k in
+                        std::iter::once(*m).chain(logical_modifier_keys(m).into_iter().flatten())
+                    {
+                        if keys.contains(&k) && !is_virtual_code(&k) && !released.contains(&k) {
+                            released.push(k);
+                        }
+                    }
+                }
+                for key in &released {
+                    keys.remove(key);
+                }
+                let consumed = |code: &KeyCode| {
+                    matches!(code, EventCode::EV_KEY(_)) && !is_modifier_code(code, modifiers)
+                };
+                for i in input {
+                    keys.remove(&i.code);
+                    for side in logical_modifier_keys(&i.code).iter().flatten() {
+                        keys.remove(side);
+                    }
+                    if consumed(&i.code) {
+                        available.remove(&i.code);
+                    }
+                }
+                for o in output {
+                    keys.insert(o.code);
+                    if is_virtual_code(&o.code) {
+                        available.insert(o.code);
+                    }
+                    if consumed(&o.code) {
+                        available.remove(&o.code);
+                    }
+                }
+                if *in_order {
+                    ordered.extend(output.iter().map(|o| o.code));
+                }
+            }
+            keys.retain(|code| !is_virtual_code(code));
+            (keys, ordered)
+        }
+
+        pub fn compute_keys_in_order(rules: &RuleSet, pressed: &[KeyCode]) -> Vec<KeyCode> {
+            let (keys, ordered) = compute_keys(rules, pressed);
+            let mut keys: Vec<KeyCode> = keys.into_iter().collect();
+            // Sorting by code first makes ties come out as they do from
+            // the bitset, which is ordered by slot
+            keys.sort_by_key(|key| rules.space.slot(key));
+            keys.sort_by_key(|key| press_rank(key, &rules.modifiers, &ordered));
+            keys
+        }
+
+        pub fn lookup_mapping(
+            rules: &RuleSet,
+            pressed: &[KeyCode],
+            code: KeyCode,
+            value: i32,
+        ) -> Option<usize> {
+            let (mappings, modifiers) = (&rules.mappings, &rules.modifiers);
+            let mut active = apply_dual_roles(mappings, pressed, &mut vec![]);
+            add_logical_modifiers(&mut active);
+            let mut held = active.clone();
+            held.extend(pressed.iter().cloned());
+            let mut best: Option<(usize, usize)> = None;
+            for (idx, map) in mappings.iter().enumerate() {
+                match map {
+                    Mapping::DualRole { input, unless, .. } => {
+                        if input.contains(&code)
+                            && input.iter().all(|k| *k == code || held.contains(k))
+                            && !unless_held(unless, &held_before(pressed, input))
+                        {
+                            return Some(idx);
+                        }
+                    }
+                    Mapping::Remap { input, .. } => {
+                        if remap_excluded(map, &active, modifiers) {
+                            continue;
+                        }
+                        let code_matched = input.iter().any(|i| {
+                            i.code == code
+                                && match i.code {
+                                    EventCode::EV_KEY(_) => true,
+                                    _ => {
+                                        i.scale == 0 || i.scale.is_negative() == value.is_negative()
+                                    }
+                                }
+                        });
+                        let all_held = input
+                            .iter()
+                            .all(|i| i.code == code || held.contains(&i.code));
+                        if code_matched && all_held && best.is_none_or(|(_, len)| input.len() > len)
+                        {
+                            best = Some((idx, input.len()));
+                        }
+                    }
+                }
+            }
+            best.map(|(idx, _)| idx)
+        }
+    }
+
+    fn rules(name: &str, toml: &str) -> RuleSet {
+        let config = MappingConfig::from_toml(name, toml);
+        RuleSet::new(config.mappings, config.modifiers).unwrap()
+    }
+
+    fn keys(codes: &[EV_KEY]) -> Vec<KeyCode> {
+        codes.iter().map(|code| EventCode::EV_KEY(*code)).collect()
+    }
+
+    #[test]
+    fn only_suppress_rules_and_chords_silence_their_inputs() {
+        let rules = rules(
+            "silence",
+            r#"
+            [[dual_role]]
+            input = "KEY_RIGHTALT+KEY_SPACE"
+            hold = ["KEY_RIGHTALT"]
+            tap = ["KEY_F13"]
+
+            [[remap]]
+            input = ["SHIFT", "KEY_BACKSPACE"]
+            output = ["KEY_DELETE"]
+            suppress = true
+
+            [[remap]]
+            input = ["SHIFT", "KEY_ESC"]
+            output = ["SHIFT", "KEY_GRAVE"]
+            "#,
+        );
+        let silenced = |pressed: &[EV_KEY]| {
+            let (_, silenced) =
+                rules.compute_keys_silencing(&keys(pressed), &mut vec![], &mut vec![]);
+            let mut silenced: Vec<KeyCode> = silenced.iter().collect();
+            silenced.sort_by_key(code_name);
+            silenced
+        };
+        assert_eq!(
+            rules.compute_keys_in_order(&keys(&[KEY_LEFTSHIFT, KEY_BACKSPACE])),
+            keys(&[KEY_DELETE])
+        );
+        // The modifiers of a suppress rule are left alone
+        assert_eq!(
+            silenced(&[KEY_LEFTSHIFT, KEY_BACKSPACE]),
+            keys(&[KEY_BACKSPACE])
+        );
+        assert_eq!(silenced(&[KEY_LEFTSHIFT, KEY_ESC]), keys(&[]));
+        assert_eq!(
+            silenced(&[KEY_RIGHTALT, KEY_SPACE]),
+            keys(&[KEY_RIGHTALT, KEY_SPACE])
+        );
+        assert_eq!(silenced(&[KEY_RIGHTALT]), keys(&[]));
+    }
+
+    #[test]
+    fn unnamed_key_codes_are_passed_through() {
+        let rules = rules(
+            "unnamed",
+            r#"
+            [[remap]]
+            input = ["KEY_A"]
+            output = ["KEY_B"]
+            "#,
+        );
+        // evdev-rs has no name for this code, so it reports it as EV_UNK
+        let unnamed = int_to_event_code(EventType::EV_KEY as u32, 84);
+        assert!(matches!(unnamed, EventCode::EV_UNK { .. }));
+        let pressed = [EventCode::EV_KEY(KEY_A), unnamed];
+        assert_eq!(
+            rules.compute_keys_in_order(&pressed),
+            [EventCode::EV_KEY(KEY_B), unnamed]
+        );
+        assert_eq!(rules.compute_keys_in_order(&[unnamed]), [unnamed]);
+    }
+
+    /// Every sequence of up to `len` distinct keys from `pool`
+    fn sequences(pool: &[KeyCode], len: usize) -> Vec<Vec<KeyCode>> {
+        let mut all: Vec<Vec<KeyCode>> = vec![vec![]];
+        let mut last: Vec<Vec<KeyCode>> = vec![vec![]];
+        for _ in 0..len {
+            let mut next = vec![];
+            for seq in &last {
+                for key in pool.iter().filter(|k| !seq.contains(k)) {
+                    let mut longer = seq.clone();
+                    longer.push(*key);
+                    next.push(longer);
+                }
+            }
+            all.extend(next.iter().cloned());
+            last = next;
+        }
+        all
+    }
+
+    #[test]
+    fn indexed_lookups_match_the_linear_scan() {
+        let rules = rules(
+            "linear",
+            r#"
+            [virtual_modifiers]
+            NAV = []
+
+            [[dual_role]]
+            input = "KEY_CAPSLOCK"
+            hold = ["NAV"]
+            tap = ["KEY_ESC"]
+
+            [[dual_role]]
+            input = "KEY_RIGHTALT+KEY_SPACE"
+            hold = ["KEY_RIGHTALT", "KEY_LEFTSHIFT"]
+            tap = ["KEY_F13"]
+            ordered = true
+
+            [[dual_role]]
+            input = "KEY_TAB"
+            hold = ["KEY_LEFTMETA"]
+            tap = ["KEY_TAB"]
+            unless = ["CTRL"]
+
+            # Overlapping chords, the longer after the shorter
+            [[remap]]
+            input = ["KEY_J", "KEY_K"]
+            output = ["KEY_ESC"]
+
+            [[remap]]
+            input = ["KEY_J", "KEY_K", "KEY_L"]
+            output = ["KEY_ENTER"]
+
+            [[remap]]
+            input = ["KEY_K", "KEY_L"]
+            output = ["KEY_TAB"]
+
+            # Side-agnostic modifiers, which match either key
+            [[remap]]
+            input = ["CTRL", "KEY_H"]
+            output = ["KEY_BACKSPACE"]
+
+            [[remap]]
+            input = ["KEY_LEFTCTRL", "KEY_J"]
+            output = ["KEY_DOWN"]
+            modifiers = "exact"
+
+            [[remap]]
+            input = ["SHIFT", "KEY_H"]
+            output = ["KEY_LEFTCTRL", "KEY_HOME"]
+            modifiers = "consume"
+            ordered = true
+
+            [[remap]]
+            input = ["ALT", "KEY_K"]
+            output = ["KEY_UP"]
+            mask = ["SHIFT"]
+            unless = ["KEY_RIGHTCTRL"]
+
+            [[remap]]
+            input = ["KEY_L"]
+            output = ["KEY_RIGHT"]
+            exact = true
+
+            # Codes in the EXTRA_SLOTS range: a virtual modifier, a
+            # scancode and the axes
+            [[remap]]
+            input = ["NAV", "KEY_H"]
+            output = ["KEY_LEFT"]
+
+            [[remap]]
+            input = ["NAV", "KEY_J"]
+            output = ["NAV", "KEY_PAGEDOWN"]
+
+            [[remap]]
+            input = ["SCAN_0x70039"]
+            output = ["KEY_LEFTCTRL"]
+
+            [[remap]]
+            input = ["REL_WHEEL+"]
+            output = ["KEY_VOLUMEUP"]
+
+            [[remap]]
+            input = ["CTRL", "REL_WHEEL-"]
+            output = ["KEY_VOLUMEDOWN"]
+
+            [[remap]]
+            input = ["NAV", "REL_X+2"]
+            output = ["REL_X+1"]
+            "#,
+        );
+        let mut pool = keys(&[
+            KEY_CAPSLOCK,
+            KEY_RIGHTALT,
+            KEY_SPACE,
+            KEY_TAB,
+            KEY_LEFTCTRL,
+            KEY_RIGHTCTRL,
+            KEY_LEFTSHIFT,
+            KEY_LEFTALT,
+            KEY_H,
+            KEY_J,
+            KEY_K,
+            KEY_L,
+        ]);
+        pool.push(scan_code(0x70039));
+        let events = [
+            (EventCode::EV_REL(EV_REL::REL_WHEEL), 1),
+            (EventCode::EV_REL(EV_REL::REL_WHEEL), -1),
+            (EventCode::EV_REL(EV_REL::REL_X), 3),
+            (EventCode::EV_REL(EV_REL::REL_Y), 1),
+        ];
+        let mut matched = HashSet::new();
+        for pressed in sequences(&pool, 4) {
+            let (expected, _) = linear::compute_keys(&rules, &pressed);
+            assert_eq!(
+                rules.compute_keys(&pressed, &mut ()).to_set(),
+                expected,
+                "{:?}",
+                pressed
+            );
+            assert_eq!(
+                rules.compute_keys_in_order(&pressed),
+                linear::compute_keys_in_order(&rules, &pressed),
+                "{:?}",
+                pressed
+            );
+            let Some((last, before)) = pressed.split_last() else {
+                continue;
+            };
+            for value in [1, 2] {
+                let expected = linear::lookup_mapping(&rules, before, *last, value);
+                assert_eq!(
+                    rules.lookup_mapping(before, *last, value),
+                    expected,
+                    "{:?}",
+                    pressed
+                );
+                matched.extend(expected);
+            }
+            for (code, value) in events {
+                let expected = linear::lookup_mapping(&rules, &pressed, code, value);
+                assert_eq!(
+                    rules.lookup_mapping(&pressed, code, value),
+                    expected,
+                    "{:?} {:?} {}",
+                    pressed,
+                    code,
+                    value
+                );
+                matched.extend(expected);
+            }
+        }
+        // Each rule was looked up at least once
+        assert_eq!(matched.len(), rules.mappings.len());
+    }
+
+    #[test]
+    fn compute_keys_applies_the_rules() {
+        let rules = rules(
+            "compute",
+            r#"
+            [virtual_modifiers]
+            NAV = []
+
+            [[dual_role]]
+            input = "KEY_CAPSLOCK"
+            hold = ["NAV"]
+            tap = ["KEY_ESC"]
+
+            [[remap]]
+            input = ["NAV", "KEY_H"]
+            output = ["KEY_LEFT"]
+
+            [[remap]]
+            input = ["KEY_J", "KEY_K"]
+            output = ["KEY_ESC"]
+
+            [[remap]]
+            input = ["KEY_J", "KEY_K", "KEY_L"]
+            output = ["KEY_ENTER"]
+
+            [[remap]]
+            input = ["SHIFT", "KEY_BACKSPACE"]
+            output = ["KEY_DELETE"]
+
+            [[remap]]
+            input = ["KEY_LEFTCTRL", "KEY_U"]
+            output = ["KEY_PAGEUP"]
+            modifiers = "exact"
+            "#,
+        );
+        let compute = |pressed: &[EV_KEY]| rules.compute_keys_in_order(&keys(pressed));
+        // Keys without rules pass through
+        assert_eq!(compute(&[KEY_A]), keys(&[KEY_A]));
+        // The virtual modifier of a dual role enables its rules, and
+        // is never output itself
+        assert_eq!(compute(&[KEY_CAPSLOCK]), keys(&[]));
+        assert_eq!(compute(&[KEY_CAPSLOCK, KEY_H]), keys(&[KEY_LEFT]));
+        assert_eq!(compute(&[KEY_H]), keys(&[KEY_H]));
+        // Rules apply in the order that they are written, so the
+        // shorter chord takes the keys that the longer one needs
+        assert_eq!(compute(&[KEY_J, KEY_K]), keys(&[KEY_ESC]));
+        assert_eq!(compute(&[KEY_J, KEY_K, KEY_L]), keys(&[KEY_ESC, KEY_L]));
+        // Either SHIFT key matches SHIFT, and is consumed
+        assert_eq!(
+            compute(&[KEY_RIGHTSHIFT, KEY_BACKSPACE]),
+            keys(&[KEY_DELETE])
+        );
+        // An exact rule doesn't apply while other modifiers are held
+        assert_eq!(compute(&[KEY_LEFTCTRL, KEY_U]), keys(&[KEY_PAGEUP]));
+        assert_eq!(
+            compute(&[KEY_LEFTCTRL, KEY_LEFTALT, KEY_U]),
+            keys(&[KEY_LEFTCTRL, KEY_LEFTALT, KEY_U])
+        );
+
+        let lookup = |pressed: &[EV_KEY], code: EV_KEY| {
+            rules
+                .lookup_mapping(&keys(pressed), EventCode::EV_KEY(code), 1)
+                .map(|idx| idx + 1)
+        };
+        assert_eq!(lookup(&[], KEY_CAPSLOCK), Some(1));
+        assert_eq!(lookup(&[KEY_CAPSLOCK], KEY_H), Some(2));
+        // Whereas the event is looked up in the longest chord
+        assert_eq!(lookup(&[KEY_J], KEY_K), Some(3));
+        assert_eq!(lookup(&[KEY_J, KEY_K], KEY_L), Some(4));
+        assert_eq!(lookup(&[], KEY_A), None);
+    }
+
+    #[test]
+    fn too_many_extra_codes_are_an_error() {
+        let mut toml = String::new();
+        for scan in 0..=EXTRA_SLOTS {
+            toml.push_str(&format!(
+                "[[remap]]\ninput = [\"SCAN_0x{:x}\"]\noutput = [\"KEY_A\"]\n",
+                0x70000 + scan
+            ));
+        }
+        let config = MappingConfig::from_toml("extra-slots", &toml);
+        let err = RuleSet::new(config.mappings, config.modifiers).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "the configuration refers to more than 256 scancodes, virtual modifiers and axes"
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use crate::mapping::{RuntimeConfig, Scheduler};
+use anyhow::{Context, Result};
+use evdev_rs::TimeVal;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Apply the `[runtime]` settings to the calling thread.
+/// These need root, or CAP_SYS_NICE and CAP_IPC_LOCK.
+pub fn configure_thread(config: &RuntimeConfig) -> Result<()> {
+    if let Some(cpu) = config.cpu {
+        if cpu >= libc::CPU_SETSIZE as usize {
+            anyhow::bail!("there is no CPU {}", cpu);
+        }
+        let res = unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            libc::CPU_SET(cpu, &mut set);
+            libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+        };
+        if res != 0 {
+            return Err(std::io::Error::last_os_error())
+                .context(format!("pinning the remapper to CPU {}", cpu));
+        }
+        log::info!("Pinned the remapper to CPU {}", cpu);
+    }
+
+    if config.lock_memory {
+        // Lift the limit on locked memory, as MCL_FUTURE makes later
+        // allocations fail once it is reached, including after the
+        // privileges have been dropped
+        let unlimited = libc::rlimit {
+            rlim_cur: libc::RLIM_INFINITY,
+            rlim_max: libc::RLIM_INFINITY,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_MEMLOCK, &unlimited) } != 0 {
+            log::warn!(
+                "Failed to lift the limit on locked memory: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("locking the remapper in memory");
+        }
+        log::info!("Locked the remapper in memory");
+    }
+
+    let (policy, name) = match config.scheduler {
+        Scheduler::Other => {
+            if config.priority.is_some() {
+                log::warn!("The [runtime] priority only applies to the fifo and rr schedulers");
+            }
+            return Ok(());
+        }
+        Scheduler::Fifo => (libc::SCHED_FIFO, "SCHED_FIFO"),
+        Scheduler::Rr => (libc::SCHED_RR, "SCHED_RR"),
+    };
+    let priority = config.priority.unwrap_or(50);
+    let param = libc::sched_param {
+        sched_priority: priority as libc::c_int,
+    };
+    let res = unsafe { libc::pthread_setschedparam(libc::pthread_self(), policy, &param) };
+    if res != 0 {
+        return Err(std::io::Error::from_raw_os_error(res)).context(format!(
+            "switching the remapper to {} priority {}",
+            name, priority
+        ));
+    }
+    log::info!("Switched the remapper to {} priority {}", name, priority);
+    Ok(())
+}
+
+/// Measures how long input frames take to reach the output device,
+/// from the timestamp of the input's `SYN_REPORT` to when the output
+/// frame has been written, and logs a summary every so often
+pub struct LatencyLog {
+    /// How often to log; zero disables the measurements
+    interval: Duration,
+    since: Instant,
+    frames: u32,
+    total: Duration,
+    max: Duration,
+}
+
+impl LatencyLog {
+    pub fn new(interval_secs: u64) -> Self {
+        Self {
+            interval: Duration::from_secs(interval_secs),
+            since: Instant::now(),
+            frames: 0,
+            total: Duration::ZERO,
+            max: Duration::ZERO,
+        }
+    }
+
+    /// Record that the frame read at `input` has just been written
+    pub fn record(&mut self, input: &TimeVal) {
+        if self.interval.is_zero() {
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        if let Some(summary) = self.add(input, now) {
+            log::info!("{}", summary);
+        }
+    }
+
+    /// Record that the frame read at `input` was written at `now`,
+    /// both since the epoch, returning the summary if one is due
+    fn add(&mut self, input: &TimeVal, now: Duration) -> Option<String> {
+        if self.interval.is_zero() {
+            return None;
+        }
+        // Input timestamps use CLOCK_REALTIME, so this can be negative
+        // if the clock was stepped; those frames are left out
+        let input = Duration::new(input.tv_sec.max(0) as u64, 0)
+            + Duration::from_micros(input.tv_usec.max(0) as u64);
+        if let Some(latency) = now.checked_sub(input) {
+            self.frames += 1;
+            self.total += latency;
+            self.max = self.max.max(latency);
+        }
+
+        if self.since.elapsed() < self.interval {
+            return None;
+        }
+        let summary = (self.frames > 0).then(|| {
+            format!(
+                "Latency over the last {}s: {} frames, {}µs on average, {}µs at most",
+                self.since.elapsed().as_secs(),
+                self.frames,
+                (self.total / self.frames).as_micros(),
+                self.max.as_micros()
+            )
+        });
+        *self = Self::new(self.interval.as_secs());
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The time since the epoch of `input` plus `micros`
+    fn after(input: &TimeVal, micros: u64) -> Duration {
+        Duration::new(input.tv_sec as u64, input.tv_usec as u32 * 1000)
+            + Duration::from_micros(micros)
+    }
+
+    #[test]
+    fn latency_is_summarized_every_interval() {
+        let input = TimeVal::new(1_000, 500);
+        let mut latency = LatencyLog::new(60);
+        assert_eq!(latency.add(&input, after(&input, 100)), None);
+        assert_eq!(latency.add(&input, after(&input, 300)), None);
+        // A frame from before a clock step is left out
+        assert_eq!(
+            latency.add(&TimeVal::new(2_000, 0), after(&input, 200)),
+            None
+        );
+        assert_eq!(latency.frames, 2);
+        assert_eq!(latency.max, Duration::from_micros(300));
+
+        latency.since -= Duration::from_secs(60);
+        assert_eq!(
+            latency.add(&input, after(&input, 200)).unwrap(),
+            "Latency over the last 60s: 3 frames, 200µs on average, 300µs at most"
+        );
+        // The next interval starts afresh
+        assert_eq!(latency.frames, 0);
+        assert_eq!(latency.max, Duration::ZERO);
+    }
+
+    #[test]
+    fn latency_isnt_measured_when_disabled() {
+        let input = TimeVal::new(1_000, 0);
+        let mut latency = LatencyLog::new(0);
+        latency.since -= Duration::from_secs(60);
+        assert_eq!(latency.add(&input, after(&input, 100)), None);
+        assert_eq!(latency.frames, 0);
+    }
+}
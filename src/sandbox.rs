@@ -0,0 +1,414 @@
+use crate::mapping::SandboxConfig;
+use anyhow::{Context, Result};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+use seccomp::restrict_syscalls;
+
+// The Landlock ABI, from linux/landlock.h, which libc doesn't include
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+/// The filesystem rights of ABI version 1, from EXECUTE to MAKE_SYM
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_NET_BIND_TCP: u64 = 1 << 0;
+const ACCESS_NET_CONNECT_TCP: u64 = 1 << 1;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+    handled_access_net: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: RawFd,
+}
+
+fn set_no_new_privs() -> Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("setting PR_SET_NO_NEW_PRIVS");
+    }
+    Ok(())
+}
+
+/// Restrict the remapper as far as `config` asks and the kernel allows:
+/// Landlock limits the files it can open to the input devices, uinput,
+/// `config_files` and the files in `fragment_dir`, and a seccomp filter
+/// makes the system calls that the read loop doesn't need fail with
+/// EPERM.
+pub fn restrict(
+    config: &SandboxConfig,
+    config_files: &[PathBuf],
+    fragment_dir: &Path,
+) -> Result<()> {
+    // Both need this when the remapper isn't privileged
+    set_no_new_privs()?;
+    if config.landlock {
+        restrict_paths(config_files, fragment_dir)?;
+    }
+    if config.seccomp {
+        restrict_syscalls()?;
+    }
+    Ok(())
+}
+
+/// Returns true if `err` was caused by a file that couldn't be opened,
+/// which in the sandbox is one that it doesn't allow
+pub fn denied(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|err| err.kind() == std::io::ErrorKind::PermissionDenied)
+    })
+}
+
+fn restrict_paths(config_files: &[PathBuf], fragment_dir: &Path) -> Result<()> {
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 0 {
+        log::warn!(
+            "Landlock is not available ({}); file access is not restricted",
+            std::io::Error::last_os_error()
+        );
+        return Ok(());
+    }
+
+    let mut handled_access_fs = ACCESS_FS_V1;
+    if abi >= 2 {
+        handled_access_fs |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        handled_access_fs |= ACCESS_FS_TRUNCATE;
+    }
+    // With no rules for them, TCP sockets can't be bound or connected
+    let handled_access_net = if abi >= 4 {
+        ACCESS_NET_BIND_TCP | ACCESS_NET_CONNECT_TCP
+    } else {
+        0
+    };
+    let attr = RulesetAttr {
+        handled_access_fs,
+        handled_access_net,
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error()).context("creating the Landlock ruleset");
+    }
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+
+    let read_write = ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE;
+    let mut rules: Vec<(&Path, u64)> = vec![
+        (Path::new("/dev/input"), read_write | ACCESS_FS_READ_DIR),
+        (Path::new("/dev/uinput"), read_write),
+        // libevdev looks up the node of an output device that it creates
+        (
+            Path::new("/sys/devices/virtual/input"),
+            ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR,
+        ),
+    ];
+    // A rule for a file covers that file rather than its path, so a
+    // file that an editor replaces, or one that a reload includes for
+    // the first time, can't be read; only fragments can be added
+    for file in config_files {
+        rules.push((file, ACCESS_FS_READ_FILE));
+    }
+    rules.push((fragment_dir, ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR));
+    for (path, access) in rules {
+        add_path_rule(&ruleset, path, access)
+            .context(format!("allowing access to {}", path.display()))?;
+    }
+
+    if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("enforcing the Landlock ruleset");
+    }
+    log::info!("Restricted file access with Landlock ABI version {}", abi);
+    Ok(())
+}
+
+/// Allow `access` to `path` and everything beneath it.  Paths that
+/// don't exist are skipped.
+fn add_path_rule(ruleset: &OwnedFd, path: &Path, access: u64) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() == std::io::ErrorKind::NotFound {
+            return Ok(());
+        }
+        return Err(err.into());
+    }
+    let parent = unsafe { OwnedFd::from_raw_fd(fd) };
+    let is_dir = path.is_dir();
+    let attr = PathBeneathAttr {
+        // Only some of the rights apply to a file
+        allowed_access: if is_dir {
+            access
+        } else {
+            access & !ACCESS_FS_READ_DIR
+        },
+        parent_fd: parent.as_raw_fd(),
+    };
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            LANDLOCK_RULE_PATH_BENEATH,
+            &attr,
+            0,
+        )
+    };
+    if res != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// The seccomp filter lists the allowed system calls by number, and
+/// those differ between architectures; only these have a list
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod seccomp {
+    use anyhow::{Context, Result};
+
+    /// The system calls made by the read loop, including reloading the
+    /// configuration, logging, and releasing the devices when it stops.
+    /// The filter only looks at the arguments of `ioctl`; `openat` and
+    /// the other calls that take a path are allowed for any path, and
+    /// it is Landlock that limits which files can be opened.
+    const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_read,
+        libc::SYS_readv,
+        libc::SYS_write,
+        libc::SYS_writev,
+        libc::SYS_close,
+        libc::SYS_ppoll,
+        libc::SYS_fcntl,
+        libc::SYS_lseek,
+        libc::SYS_openat,
+        libc::SYS_newfstatat,
+        libc::SYS_fstat,
+        libc::SYS_statx,
+        libc::SYS_getdents64,
+        libc::SYS_readlinkat,
+        libc::SYS_getcwd,
+        libc::SYS_inotify_add_watch,
+        libc::SYS_inotify_rm_watch,
+        libc::SYS_clock_gettime,
+        libc::SYS_gettimeofday,
+        libc::SYS_getrandom,
+        libc::SYS_futex,
+        libc::SYS_sched_yield,
+        libc::SYS_brk,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_sigaltstack,
+        libc::SYS_restart_syscall,
+        libc::SYS_getpid,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_poll,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_open,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_stat,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_lstat,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_readlink,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_getdents,
+    ];
+
+    /// The `AUDIT_ARCH_*` value that seccomp reports for this build
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    fn bpf_stmt(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    // Offsets into struct seccomp_data
+    const NR: u32 = 0;
+    const ARCH: u32 = 4;
+    /// The low word of the second argument, which is the request of an
+    /// `ioctl`; both architectures are little-endian
+    const ARG1_LOW: u32 = 24;
+
+    /// `EVIOCGRAB`, which releases the grab on an input when stopping
+    const EVIOCGRAB: u32 = 0x4004_4590;
+    /// The type of the uinput requests, `UI_*`
+    const UI_TYPE: u32 = 0x5500;
+    /// The direction and type of the `EVIOCG*` requests, which read the
+    /// state of an input when it is resynchronized; their size varies
+    const EVIOCG: u32 = 0x8000_4500;
+    const DIR_TYPE_MASK: u32 = 0xc000_ff00;
+
+    const LD: u32 = libc::BPF_LD | libc::BPF_W | libc::BPF_ABS;
+    const JEQ: u32 = libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K;
+    const AND: u32 = libc::BPF_ALU | libc::BPF_AND | libc::BPF_K;
+    const RET: u32 = libc::BPF_RET | libc::BPF_K;
+    const DENY: u32 = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+    /// The filter program: kill the process for a foreign architecture,
+    /// allow `ioctl` for the input and uinput requests only, allow the
+    /// `ALLOWED_SYSCALLS`, and fail anything else with EPERM
+    fn filter() -> Vec<libc::sock_filter> {
+        let mut filter = vec![
+            bpf_stmt(LD, ARCH),
+            bpf_jump(JEQ, AUDIT_ARCH, 1, 0),
+            bpf_stmt(RET, libc::SECCOMP_RET_KILL_PROCESS),
+            bpf_stmt(LD, NR),
+            // Skip the 9 instructions that check the ioctl request
+            bpf_jump(JEQ, libc::SYS_ioctl as u32, 0, 9),
+            bpf_stmt(LD, ARG1_LOW),
+            bpf_jump(JEQ, EVIOCGRAB, 5, 0),
+            bpf_stmt(AND, 0xff00),
+            bpf_jump(JEQ, UI_TYPE, 3, 0),
+            bpf_stmt(LD, ARG1_LOW),
+            bpf_stmt(AND, DIR_TYPE_MASK),
+            bpf_jump(JEQ, EVIOCG, 0, 1),
+            bpf_stmt(RET, libc::SECCOMP_RET_ALLOW),
+            bpf_stmt(RET, DENY),
+        ];
+        for nr in ALLOWED_SYSCALLS {
+            filter.push(bpf_jump(JEQ, *nr as u32, 0, 1));
+            filter.push(bpf_stmt(RET, libc::SECCOMP_RET_ALLOW));
+        }
+        filter.push(bpf_stmt(RET, DENY));
+        filter
+    }
+
+    pub fn restrict_syscalls() -> Result<()> {
+        let mut filter = filter();
+        let prog = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+        let res = unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &prog as *const libc::sock_fprog,
+            )
+        };
+        if res != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EINVAL) {
+                log::warn!("seccomp filters are not available; system calls are not restricted");
+                return Ok(());
+            }
+            return Err(err).context("installing the seccomp filter");
+        }
+        log::info!(
+            "Restricted the remapper to {} system calls with seccomp",
+            ALLOWED_SYSCALLS.len() + 1
+        );
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// Run `filter` for the system call `nr` with `arg1` as its
+        /// second argument, returning its verdict
+        fn run(filter: &[libc::sock_filter], arch: u32, nr: libc::c_long, arg1: u32) -> u32 {
+            // struct seccomp_data as 32-bit words
+            let mut data = [0u32; 16];
+            data[(NR / 4) as usize] = nr as u32;
+            data[(ARCH / 4) as usize] = arch;
+            data[(ARG1_LOW / 4) as usize] = arg1;
+            let mut acc = 0;
+            let mut pc = 0;
+            loop {
+                let insn = &filter[pc];
+                pc += 1;
+                match insn.code as u32 {
+                    LD => acc = data[(insn.k / 4) as usize],
+                    AND => acc &= insn.k,
+                    JEQ => {
+                        let skip = if acc == insn.k { insn.jt } else { insn.jf };
+                        pc += skip as usize;
+                    }
+                    RET => return insn.k,
+                    code => panic!("unexpected instruction {:#x}", code),
+                }
+            }
+        }
+
+        #[test]
+        fn filter_verdicts() {
+            let filter = filter();
+            let allow = libc::SECCOMP_RET_ALLOW;
+            let check = |nr, arg1| run(&filter, AUDIT_ARCH, nr, arg1);
+            assert_eq!(filter.last().map(|insn| insn.k), Some(DENY));
+            assert_eq!(
+                run(&filter, 0x4000_0003, libc::SYS_read, 0),
+                libc::SECCOMP_RET_KILL_PROCESS
+            );
+            for nr in ALLOWED_SYSCALLS {
+                assert_eq!(check(*nr, 0), allow, "{}", nr);
+            }
+            assert_eq!(check(libc::SYS_execve, 0), DENY);
+            assert_eq!(check(libc::SYS_socket, 0), DENY);
+
+            // UI_DEV_DESTROY, EVIOCGRAB and EVIOCGKEY(96)
+            assert_eq!(check(libc::SYS_ioctl, 0x5502), allow);
+            assert_eq!(check(libc::SYS_ioctl, EVIOCGRAB), allow);
+            assert_eq!(check(libc::SYS_ioctl, 0x8060_4518), allow);
+            // TIOCSTI, EVIOCSABS(0) and EVIOCREVOKE
+            assert_eq!(check(libc::SYS_ioctl, 0x5412), DENY);
+            assert_eq!(check(libc::SYS_ioctl, 0x4018_45c0), DENY);
+            assert_eq!(check(libc::SYS_ioctl, 0x4004_4591), DENY);
+        }
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn restrict_syscalls() -> Result<()> {
+    log::warn!("The seccomp filter is not available on this architecture");
+    Ok(())
+}